@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use netty::arp;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((_, payload)) = arp::Header::decode(data) {
+        let _ = arp::Ipv4Data::decode(payload);
+    }
+    if let Ok(packet) = arp::ArpPacket::new_checked(data) {
+        let _ = (packet.hwtype(), packet.protype(), packet.opcode());
+        let _ = (packet.smac(), packet.sip(), packet.dmac(), packet.dip());
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use netty::eth;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = eth::Header::decode(data);
+    if let Ok(frame) = eth::EthernetFrame::new_checked(data) {
+        let _ = (frame.dmac(), frame.smac(), frame.ethertype(), frame.payload());
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use netty::icmpv4;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((_, payload)) = icmpv4::Header::decode(data) {
+        let _ = icmpv4::EchoHeader::decode(payload);
+    }
+    if let Ok(packet) = icmpv4::Icmpv4Packet::new_checked(data) {
+        let _ = (packet.msg_type(), packet.code(), packet.echo_id(), packet.echo_seq());
+        let _ = (packet.data(), packet.verify_checksum());
+    }
+});
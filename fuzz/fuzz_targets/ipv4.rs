@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use netty::ipv4;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ipv4::Header::decode(data);
+    if let Ok(packet) = ipv4::Ipv4Packet::new_checked(data) {
+        let _ = (packet.header_len(), packet.total_len(), packet.fragment_offset());
+        let _ = (packet.protocol(), packet.src_addr(), packet.dst_addr());
+        let _ = (packet.payload(), packet.verify_checksum());
+    }
+});
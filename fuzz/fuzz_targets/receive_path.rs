@@ -0,0 +1,33 @@
+#![no_main]
+//! Feeds frames through a whole stack running on a `MemoryDevice`. The input is a series
+//! of frames, each preceded by its length as a big endian `u16`.
+use libfuzzer_sys::fuzz_target;
+use netty::{eth, MemoryDevice, NettyStack, PacketPool, PACKET_SIZE};
+
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut buf = vec![0u8; PACKET_SIZE * 8];
+    let pool = PacketPool::<8>::new(&mut buf).unwrap();
+    let (device, mut handle) = MemoryDevice::new();
+    let mut stack = NettyStack::with_device(device, &pool);
+    stack.set_rx_checksum_offload(data.first().map_or(false, |byte| byte & 1 == 1));
+
+    let mut data = data.get(1..).unwrap_or_default();
+    while data.len() >= 2 {
+        let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+        let len = std::cmp::min(len, data.len() - 2);
+        handle.inject(&data[2..2 + len]);
+        data = &data[2 + len..];
+    }
+    handle.close();
+    runtime.block_on(stack.run()).unwrap();
+
+    // Whatever the stack sends must be a well formed frame which fits on the wire
+    while let Some(frame) = handle.try_recv() {
+        assert!(frame.len() <= PACKET_SIZE);
+        eth::EthernetFrame::new_checked(&frame[..]).unwrap();
+    }
+});
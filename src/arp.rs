@@ -1,11 +1,12 @@
-use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use crate::error::{check_decode_len, check_encode_len, Layer, NettyError, Result};
+use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt, WriteBytesExt};
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
-use std::io;
 use std::io::{Read, Write};
 use std::net::Ipv4Addr;
 
 pub const HEADER_SIZE: usize = 8;
+pub const IPV4_DATA_SIZE: usize = 20;
 
 #[repr(u16)]
 #[derive(Clone, Copy, Debug, PartialEq, FromPrimitive, ToPrimitive)]
@@ -19,13 +20,6 @@ pub enum ProtocolType {
     Ipv4 = 0x0800,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub struct CacheEntry {
-    pub hwtype: HwType,
-    pub ip: Ipv4Addr,
-    pub mac: [u8; 6],
-}
-
 #[repr(u16)]
 #[derive(Clone, Copy, Debug, PartialEq, FromPrimitive, ToPrimitive)]
 pub enum Opcode {
@@ -45,21 +39,25 @@ pub struct Header {
 }
 
 impl Header {
-    pub fn decode(buf: &[u8]) -> io::Result<(Self, &[u8])> {
+    pub fn decode(buf: &[u8]) -> Result<(Self, &[u8])> {
+        check_decode_len(Layer::Arp, buf, HEADER_SIZE)?;
         let mut cursor = std::io::Cursor::new(buf);
-        let hwtype = match FromPrimitive::from_u16(cursor.read_u16::<NetworkEndian>()?) {
+        let raw_hwtype = cursor.read_u16::<NetworkEndian>()?;
+        let hwtype = match FromPrimitive::from_u16(raw_hwtype) {
             Some(hwtype) => hwtype,
-            None => return Err(io::ErrorKind::Unsupported.into()),
+            None => return Err(unknown_value("hardware type", raw_hwtype)),
         };
-        let protype = match FromPrimitive::from_u16(cursor.read_u16::<NetworkEndian>()?) {
+        let raw_protype = cursor.read_u16::<NetworkEndian>()?;
+        let protype = match FromPrimitive::from_u16(raw_protype) {
             Some(protype) => protype,
-            None => return Err(io::ErrorKind::Unsupported.into()),
+            None => return Err(unknown_value("protocol type", raw_protype)),
         };
         let hwsize = cursor.read_u8()?;
         let prosize = cursor.read_u8()?;
-        let opcode = match FromPrimitive::from_u16(cursor.read_u16::<NetworkEndian>()?) {
+        let raw_opcode = cursor.read_u16::<NetworkEndian>()?;
+        let opcode = match FromPrimitive::from_u16(raw_opcode) {
             Some(opcode) => opcode,
-            None => return Err(io::ErrorKind::InvalidData.into()),
+            None => return Err(unknown_value("opcode", raw_opcode)),
         };
         Ok((
             Header {
@@ -73,7 +71,8 @@ impl Header {
         ))
     }
 
-    pub fn encode(self, buf: &mut [u8]) -> io::Result<usize> {
+    pub fn encode(self, buf: &mut [u8]) -> Result<usize> {
+        check_encode_len(Layer::Arp, buf, HEADER_SIZE)?;
         let mut cursor = std::io::Cursor::new(buf);
         cursor.write_u16::<NetworkEndian>(self.hwtype.to_u16().unwrap())?;
         cursor.write_u16::<NetworkEndian>(self.protype.to_u16().unwrap())?;
@@ -92,7 +91,8 @@ pub struct Ipv4Data {
 }
 
 impl Ipv4Data {
-    pub fn decode(buf: &[u8]) -> io::Result<(Self, &[u8])> {
+    pub fn decode(buf: &[u8]) -> Result<(Self, &[u8])> {
+        check_decode_len(Layer::Arp, buf, IPV4_DATA_SIZE)?;
         let mut cursor = std::io::Cursor::new(buf);
         let mut smac = [0u8; 6];
         cursor.read_exact(&mut smac)?;
@@ -111,16 +111,147 @@ impl Ipv4Data {
                 dmac,
                 dip,
             },
-            &buf[20..],
+            &buf[IPV4_DATA_SIZE..],
         ))
     }
 
-    pub fn encode(self, buf: &mut [u8]) -> io::Result<usize> {
+    pub fn encode(self, buf: &mut [u8]) -> Result<usize> {
+        check_encode_len(Layer::Arp, buf, IPV4_DATA_SIZE)?;
         let mut cursor = std::io::Cursor::new(buf);
         cursor.write_all(&self.smac)?;
         cursor.write_all(&self.sip.octets())?;
         cursor.write_all(&self.dmac)?;
         cursor.write_all(&self.dip.octets())?;
-        Ok(20)
+        Ok(IPV4_DATA_SIZE)
+    }
+}
+
+fn unknown_value(field: &'static str, value: u16) -> NettyError {
+    NettyError::UnknownValue {
+        layer: Layer::Arp,
+        field,
+        value: value.into(),
+    }
+}
+
+/// A view of an Ethernet/IPv4 ARP packet which reads and writes its fields directly in
+/// `buffer`. `new_checked` makes sure the buffer holds the header and addresses.
+#[derive(Clone, Copy, Debug)]
+pub struct ArpPacket<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> ArpPacket<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    pub fn new_checked(buffer: T) -> Result<Self> {
+        check_decode_len(Layer::Arp, buffer.as_ref(), HEADER_SIZE + IPV4_DATA_SIZE)?;
+        Ok(Self { buffer })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    pub fn hwtype(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[0..2])
+    }
+
+    pub fn protype(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[2..4])
+    }
+
+    pub fn hwsize(&self) -> u8 {
+        self.buffer.as_ref()[4]
+    }
+
+    pub fn prosize(&self) -> u8 {
+        self.buffer.as_ref()[5]
+    }
+
+    pub fn opcode(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[6..8])
+    }
+
+    pub fn smac(&self) -> [u8; 6] {
+        self.buffer.as_ref()[8..14].try_into().unwrap()
+    }
+
+    pub fn sip(&self) -> Ipv4Addr {
+        let octets: [u8; 4] = self.buffer.as_ref()[14..18].try_into().unwrap();
+        Ipv4Addr::from(octets)
+    }
+
+    pub fn dmac(&self) -> [u8; 6] {
+        self.buffer.as_ref()[18..24].try_into().unwrap()
+    }
+
+    pub fn dip(&self) -> Ipv4Addr {
+        let octets: [u8; 4] = self.buffer.as_ref()[24..28].try_into().unwrap();
+        Ipv4Addr::from(octets)
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> ArpPacket<T> {
+    pub fn set_hwtype(&mut self, hwtype: u16) {
+        NetworkEndian::write_u16(&mut self.buffer.as_mut()[0..2], hwtype);
+    }
+
+    pub fn set_protype(&mut self, protype: u16) {
+        NetworkEndian::write_u16(&mut self.buffer.as_mut()[2..4], protype);
+    }
+
+    pub fn set_hwsize(&mut self, hwsize: u8) {
+        self.buffer.as_mut()[4] = hwsize;
+    }
+
+    pub fn set_prosize(&mut self, prosize: u8) {
+        self.buffer.as_mut()[5] = prosize;
+    }
+
+    pub fn set_opcode(&mut self, opcode: u16) {
+        NetworkEndian::write_u16(&mut self.buffer.as_mut()[6..8], opcode);
+    }
+
+    pub fn set_smac(&mut self, smac: [u8; 6]) {
+        self.buffer.as_mut()[8..14].copy_from_slice(&smac);
+    }
+
+    pub fn set_sip(&mut self, sip: Ipv4Addr) {
+        self.buffer.as_mut()[14..18].copy_from_slice(&sip.octets());
+    }
+
+    pub fn set_dmac(&mut self, dmac: [u8; 6]) {
+        self.buffer.as_mut()[18..24].copy_from_slice(&dmac);
+    }
+
+    pub fn set_dip(&mut self, dip: Ipv4Addr) {
+        self.buffer.as_mut()[24..28].copy_from_slice(&dip.octets());
+    }
+}
+
+mod tests {
+    #[test]
+    fn decode_encode_round_trip() {
+        use crate::arp::*;
+        let mut rng = crate::util::Rng::new(1);
+        for _ in 0..1000 {
+            let mut buf = [0u8; HEADER_SIZE + IPV4_DATA_SIZE];
+            buf.iter_mut().for_each(|byte| *byte = rng.next_u64() as u8);
+            // Mostly use known values so that the decode usually succeeds
+            if rng.chance(0.75) {
+                buf[0..4].copy_from_slice(&[0x00, 0x01, 0x08, 0x00]);
+                buf[6..8].copy_from_slice(&(rng.next_u64() as u16 % 4 + 1).to_be_bytes());
+            }
+            if let Ok((header, payload)) = Header::decode(&buf) {
+                let (data, _) = Ipv4Data::decode(payload).unwrap();
+                let mut encoded = [0u8; HEADER_SIZE + IPV4_DATA_SIZE];
+                header.encode(&mut encoded).unwrap();
+                data.encode(&mut encoded[HEADER_SIZE..]).unwrap();
+                assert_eq!(encoded, buf);
+            }
+        }
     }
 }
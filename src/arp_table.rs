@@ -0,0 +1,346 @@
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// The most entries the table holds. Learning a new neighbor fails while it's full.
+pub const ARP_TABLE_ENTRIES: usize = 32;
+/// How long a learnt entry is used after it was last confirmed by an ARP packet
+pub const ARP_ENTRY_LIFETIME: Duration = Duration::from_secs(60);
+/// How many events a subscriber can fall behind by before it misses some
+const EVENT_QUEUE_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArpEntryState {
+    /// Learnt from ARP traffic, and expires unless it's confirmed again
+    Dynamic,
+    /// Added by the application, and kept until it's removed
+    Static,
+}
+
+/// A neighbor's MAC address as seen by `ArpTable::entries`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ArpEntry {
+    pub ip: Ipv4Addr,
+    pub mac: [u8; 6],
+    pub state: ArpEntryState,
+    /// Time since the entry was added or last confirmed
+    pub age: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArpEvent {
+    Added(ArpEntry),
+    /// An entry's MAC address or state changed. Holds the entry as it is now.
+    Changed {
+        old_mac: [u8; 6],
+        entry: ArpEntry,
+    },
+    /// A dynamic entry wasn't confirmed within `ARP_ENTRY_LIFETIME`
+    Expired(ArpEntry),
+    /// The application removed the entry
+    Removed(ArpEntry),
+    /// An ARP packet claimed `ip` for a different MAC address than the table has, which
+    /// may be a host being spoofed. If the claim was `accepted` a `Changed` event follows.
+    Conflict {
+        ip: Ipv4Addr,
+        known_mac: [u8; 6],
+        claimed_mac: [u8; 6],
+        accepted: bool,
+    },
+}
+
+/// How much the stack trusts the ARP packets it receives. The default trusts them all and
+/// learns from every one, as the stack always has; spoofing protection and rate limiting
+/// have to be switched on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ArpPolicy {
+    /// Add entries for hosts which send replies the stack never asked for. Requests for
+    /// our addresses always add their sender.
+    pub accept_unsolicited_replies: bool,
+    /// Add entries for the senders of ARP packets meant for other hosts too. Without it
+    /// only senders which ask for our addresses are added, as in RFC 826's merge rule.
+    pub learn_overheard: bool,
+    /// A dynamic entry keeps its MAC address for this long after it was last confirmed.
+    /// Static entries never change.
+    pub lock_time: Duration,
+    /// The most ARP packets handled per second from each source MAC address, or `None`
+    /// to handle every packet. Short bursts of up to this many are allowed.
+    pub rate_limit: Option<u32>,
+}
+
+impl Default for ArpPolicy {
+    fn default() -> Self {
+        Self {
+            accept_unsolicited_replies: true,
+            learn_overheard: true,
+            lock_time: Duration::ZERO,
+            rate_limit: None,
+        }
+    }
+}
+
+/// What `ArpTable::learn` did with a mapping
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Learnt {
+    Added,
+    /// The table already had the same mapping
+    Confirmed,
+    /// There was no entry and none was created
+    Ignored,
+    /// The table had a different MAC address for the IP address
+    Conflict {
+        accepted: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    ip: Ipv4Addr,
+    mac: [u8; 6],
+    state: ArpEntryState,
+    updated_at: Instant,
+}
+
+impl Entry {
+    fn snapshot(&self, now: Instant) -> ArpEntry {
+        ArpEntry {
+            ip: self.ip,
+            mac: self.mac,
+            state: self.state,
+            age: now.saturating_duration_since(self.updated_at),
+        }
+    }
+}
+
+/// The stack's neighbor cache, mapping IPv4 addresses on the segment to MAC addresses.
+/// It's shared with the application through an `Arc` so it can be inspected and seeded
+/// while the stack is running.
+pub struct ArpTable {
+    entries: Mutex<Vec<Entry>>,
+    events: broadcast::Sender<ArpEvent>,
+}
+
+impl Default for ArpTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ArpTable {
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(EVENT_QUEUE_LEN);
+        Self {
+            entries: Mutex::new(Vec::with_capacity(ARP_TABLE_ENTRIES)),
+            events,
+        }
+    }
+
+    /// Every entry, in the order they were added
+    pub fn entries(&self) -> Vec<ArpEntry> {
+        let now = Instant::now();
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.snapshot(now))
+            .collect()
+    }
+
+    pub fn lookup(&self, ip: Ipv4Addr) -> Option<[u8; 6]> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|entry| entry.ip == ip)
+            .map(|entry| entry.mac)
+    }
+
+    /// Adds a permanent entry, replacing any entry for `ip`. Returns false if the table
+    /// is full.
+    pub fn insert_static(&self, ip: Ipv4Addr, mac: [u8; 6]) -> bool {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.iter_mut().find(|entry| entry.ip == ip) {
+            let old_mac = entry.mac;
+            let changed = entry.mac != mac || entry.state != ArpEntryState::Static;
+            entry.mac = mac;
+            entry.state = ArpEntryState::Static;
+            entry.updated_at = now;
+            if changed {
+                self.notify(ArpEvent::Changed {
+                    old_mac,
+                    entry: entry.snapshot(now),
+                });
+            }
+            return true;
+        }
+        self.push(&mut entries, ip, mac, ArpEntryState::Static, now)
+    }
+
+    pub fn remove(&self, ip: Ipv4Addr) -> Option<ArpEntry> {
+        let mut entries = self.entries.lock().unwrap();
+        let position = entries.iter().position(|entry| entry.ip == ip)?;
+        let removed = entries.remove(position).snapshot(Instant::now());
+        self.notify(ArpEvent::Removed(removed));
+        Some(removed)
+    }
+
+    /// Removes every dynamic entry. Static entries stay until they're removed one by one.
+    pub fn flush(&self) {
+        let now = Instant::now();
+        self.entries.lock().unwrap().retain(|entry| {
+            let flushed = entry.state == ArpEntryState::Dynamic;
+            if flushed {
+                self.notify(ArpEvent::Removed(entry.snapshot(now)));
+            }
+            !flushed
+        });
+    }
+
+    /// Gives a receiver of every change made to the table from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ArpEvent> {
+        self.events.subscribe()
+    }
+
+    /// Records that `ip` was seen at `mac`. An existing dynamic entry is refreshed, but a
+    /// new one is only added if `create` is set. A different MAC address replaces the
+    /// known one only if the entry is dynamic and wasn't confirmed within `lock_time`;
+    /// either way the conflict is reported.
+    pub(crate) fn learn(
+        &self,
+        ip: Ipv4Addr,
+        mac: [u8; 6],
+        create: bool,
+        lock_time: Duration,
+    ) -> Learnt {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.iter_mut().find(|entry| entry.ip == ip) {
+            if entry.mac == mac {
+                // Static entries don't age, so there's nothing to refresh
+                if entry.state == ArpEntryState::Dynamic {
+                    entry.updated_at = now;
+                }
+                return Learnt::Confirmed;
+            }
+            let accepted = entry.state == ArpEntryState::Dynamic
+                && now.saturating_duration_since(entry.updated_at) >= lock_time;
+            log::warn!(
+                "ARP conflict: {} is at {:x?} but {:x?} claims it{}",
+                ip,
+                entry.mac,
+                mac,
+                if accepted { "" } else { ", ignoring" }
+            );
+            self.notify(ArpEvent::Conflict {
+                ip,
+                known_mac: entry.mac,
+                claimed_mac: mac,
+                accepted,
+            });
+            if accepted {
+                let old_mac = entry.mac;
+                entry.mac = mac;
+                entry.updated_at = now;
+                self.notify(ArpEvent::Changed {
+                    old_mac,
+                    entry: entry.snapshot(now),
+                });
+            }
+            return Learnt::Conflict { accepted };
+        }
+        if create && self.push(&mut entries, ip, mac, ArpEntryState::Dynamic, now) {
+            Learnt::Added
+        } else {
+            Learnt::Ignored
+        }
+    }
+
+    /// Removes dynamic entries which haven't been confirmed for `ARP_ENTRY_LIFETIME`
+    pub(crate) fn expire(&self) {
+        let now = Instant::now();
+        self.entries.lock().unwrap().retain(|entry| {
+            let expired = entry.state == ArpEntryState::Dynamic
+                && now.saturating_duration_since(entry.updated_at) >= ARP_ENTRY_LIFETIME;
+            if expired {
+                log::info!("ARP entry for {} expired", entry.ip);
+                self.notify(ArpEvent::Expired(entry.snapshot(now)));
+            }
+            !expired
+        });
+    }
+
+    /// Adds an entry for an address the table doesn't have yet
+    fn push(
+        &self,
+        entries: &mut Vec<Entry>,
+        ip: Ipv4Addr,
+        mac: [u8; 6],
+        state: ArpEntryState,
+        now: Instant,
+    ) -> bool {
+        if entries.len() >= ARP_TABLE_ENTRIES {
+            log::error!("ARP table is full!");
+            return false;
+        }
+        let entry = Entry {
+            ip,
+            mac,
+            state,
+            updated_at: now,
+        };
+        entries.push(entry);
+        self.notify(ArpEvent::Added(entry.snapshot(now)));
+        true
+    }
+
+    fn notify(&self, event: ArpEvent) {
+        // It's fine for nobody to be listening
+        let _ = self.events.send(event);
+    }
+}
+
+mod tests {
+    #[test]
+    fn static_entries_and_events() {
+        use crate::arp_table::*;
+        let table = ArpTable::new();
+        let mut events = table.subscribe();
+        let gateway = Ipv4Addr::new(10, 0, 0, 1);
+        let host = Ipv4Addr::new(10, 0, 0, 5);
+        let lock_time = Duration::from_secs(1);
+        assert!(table.insert_static(gateway, [1; 6]));
+        assert_eq!(table.learn(host, [2; 6], false, lock_time), Learnt::Ignored);
+        assert_eq!(table.learn(host, [2; 6], true, lock_time), Learnt::Added);
+        // Learnt addresses don't replace static ones
+        let refused = Learnt::Conflict { accepted: false };
+        assert_eq!(table.learn(gateway, [3; 6], true, lock_time), refused);
+        assert_eq!(table.lookup(gateway), Some([1; 6]));
+        // Nor ones which were confirmed recently
+        assert_eq!(table.learn(host, [4; 6], false, lock_time), refused);
+        let accepted = Learnt::Conflict { accepted: true };
+        assert_eq!(table.learn(host, [4; 6], false, Duration::ZERO), accepted);
+
+        table.flush();
+        let entries = table.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].state, ArpEntryState::Static);
+
+        assert!(matches!(events.try_recv(), Ok(ArpEvent::Added(entry)) if entry.ip == gateway));
+        assert!(matches!(events.try_recv(), Ok(ArpEvent::Added(entry)) if entry.ip == host));
+        for (ip, accepted) in [(gateway, false), (host, false), (host, true)] {
+            assert!(matches!(
+                events.try_recv(),
+                Ok(ArpEvent::Conflict { ip: conflict_ip, accepted: conflict_accepted, .. })
+                    if conflict_ip == ip && conflict_accepted == accepted
+            ));
+        }
+        assert!(matches!(
+            events.try_recv(),
+            Ok(ArpEvent::Changed { old_mac: [2, ..], entry }) if entry.mac == [4; 6]
+        ));
+        assert!(matches!(events.try_recv(), Ok(ArpEvent::Removed(entry)) if entry.ip == host));
+        assert!(events.try_recv().is_err());
+    }
+}
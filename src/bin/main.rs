@@ -1,6 +1,8 @@
 use futures::stream::TryStreamExt;
-use netty::NettyStack;
+use netty::{ArpEntryState, ArpTable, NettyStack};
 use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use tokio::io::AsyncBufReadExt;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -16,6 +18,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tokio::spawn(connection);
     add_address(if_name, Ipv4Addr::new(10, 0, 0, 1).into(), handle).await?;
 
+    tokio::spawn(handle_commands(netty.arp_table()));
     netty.run().await?;
     
     Ok(())
@@ -41,6 +44,41 @@ async fn add_address(
     Ok(())
 }
 
+/// Reads commands from stdin while the stack runs. `arp` prints the ARP table.
+async fn handle_commands(arp_table: Arc<ArpTable>) {
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        match line.trim() {
+            "arp" => print_arp_table(&arp_table),
+            "" => {}
+            command => println!("Unknown command: {}", command),
+        }
+    }
+}
+
+fn print_arp_table(arp_table: &ArpTable) {
+    println!("{:<16} {:<18} {:<8} {:>6}", "Address", "HWaddress", "State", "Age");
+    for entry in arp_table.entries() {
+        let mac = entry
+            .mac
+            .iter()
+            .map(|octet| format!("{:02x}", octet))
+            .collect::<Vec<_>>()
+            .join(":");
+        let state = match entry.state {
+            ArpEntryState::Dynamic => "dynamic",
+            ArpEntryState::Static => "static",
+        };
+        println!(
+            "{:<16} {:<18} {:<8} {:>5}s",
+            entry.ip.to_string(),
+            mac,
+            state,
+            entry.age.as_secs()
+        );
+    }
+}
+
 struct SimpleStdoutLogger;
 static LOGGER: SimpleStdoutLogger = SimpleStdoutLogger;
 
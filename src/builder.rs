@@ -0,0 +1,299 @@
+use crate::error::{Layer, NettyError, Result};
+use crate::packet_pool::Packet;
+use crate::{eth, icmpv4, ipv4, udp, util};
+use std::net::Ipv4Addr;
+
+const DEFAULT_TTL: u8 = 64;
+/// The Router Alert option (RFC 2113), which IGMP messages carry
+const ROUTER_ALERT_OPTION: [u8; 4] = [0x94, 0x04, 0x00, 0x00];
+
+/// Entry point for building a packet layer by layer, e.g.
+/// `PacketBuilder::ethernet(smac, dmac).ipv4(src, dst).icmpv4(msg).finalize(&mut packet)`.
+/// The packet's current data is used as the innermost payload and each layer's header is
+/// pushed into the headroom in front of it, with lengths and checksums filled in.
+pub struct PacketBuilder;
+
+impl PacketBuilder {
+    pub fn ethernet(smac: [u8; 6], dmac: [u8; 6]) -> EthernetBuilder {
+        EthernetBuilder { smac, dmac }
+    }
+
+    /// Builds an IPv4 datagram without a link layer header, as sent by applications
+    pub fn ipv4(src_addr: Ipv4Addr, dst_addr: Ipv4Addr) -> Ipv4Builder {
+        Ipv4Builder::new(None, src_addr, dst_addr)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct EthernetBuilder {
+    smac: [u8; 6],
+    dmac: [u8; 6],
+}
+
+impl EthernetBuilder {
+    pub fn ipv4(self, src_addr: Ipv4Addr, dst_addr: Ipv4Addr) -> Ipv4Builder {
+        Ipv4Builder::new(Some(self), src_addr, dst_addr)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ipv4Builder {
+    ethernet: Option<EthernetBuilder>,
+    src_addr: Ipv4Addr,
+    dst_addr: Ipv4Addr,
+    type_of_service: u8,
+    id: u16,
+    control_flags: u8,
+    time_to_live: u8,
+    router_alert: bool,
+}
+
+impl Ipv4Builder {
+    fn new(ethernet: Option<EthernetBuilder>, src_addr: Ipv4Addr, dst_addr: Ipv4Addr) -> Self {
+        Self {
+            ethernet,
+            src_addr,
+            dst_addr,
+            type_of_service: 0,
+            id: 0,
+            control_flags: ipv4::FLAG_DONT_FRAGMENT,
+            time_to_live: DEFAULT_TTL,
+            router_alert: false,
+        }
+    }
+
+    pub fn type_of_service(mut self, type_of_service: u8) -> Self {
+        self.type_of_service = type_of_service;
+        self
+    }
+
+    pub fn id(mut self, id: u16) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn time_to_live(mut self, time_to_live: u8) -> Self {
+        self.time_to_live = time_to_live;
+        self
+    }
+
+    /// Adds the Router Alert option to the header
+    pub fn router_alert(mut self) -> Self {
+        self.router_alert = true;
+        self
+    }
+
+    /// Sends the packet's data as it is, for transport messages which were already
+    /// encoded with their own checksums
+    pub fn payload(self, proto: ipv4::ProtocolType) -> DatagramBuilder {
+        DatagramBuilder {
+            ip: self,
+            transport: Transport::Raw(proto),
+        }
+    }
+
+    pub fn icmpv4(self, message: Icmpv4Message) -> DatagramBuilder {
+        DatagramBuilder {
+            ip: self,
+            transport: Transport::Icmpv4(message),
+        }
+    }
+
+    pub fn udp(self, src_port: u16, dst_port: u16) -> DatagramBuilder {
+        DatagramBuilder {
+            ip: self,
+            transport: Transport::Udp { src_port, dst_port },
+        }
+    }
+}
+
+/// The header of an ICMP message. The payload, such as echo data or the datagram an
+/// error is about, is the packet's data when the builder is finalized.
+#[derive(Clone, Copy, Debug)]
+pub struct Icmpv4Message {
+    msg_type: icmpv4::MsgType,
+    code: u8,
+    echo: Option<icmpv4::EchoHeader>,
+}
+
+impl Icmpv4Message {
+    pub fn echo_request(id: u16, seq: u16) -> Self {
+        Self {
+            msg_type: icmpv4::MsgType::EchoRequest,
+            code: 0,
+            echo: Some(icmpv4::EchoHeader { id, seq }),
+        }
+    }
+
+    pub fn echo_reply(id: u16, seq: u16) -> Self {
+        Self {
+            msg_type: icmpv4::MsgType::EchoReply,
+            code: 0,
+            echo: Some(icmpv4::EchoHeader { id, seq }),
+        }
+    }
+
+    pub fn destination_unreachable(code: u8) -> Self {
+        Self {
+            msg_type: icmpv4::MsgType::DestinationUnreachable,
+            code,
+            echo: None,
+        }
+    }
+
+    pub fn time_exceeded(code: u8) -> Self {
+        Self {
+            msg_type: icmpv4::MsgType::TimeExceeded,
+            code,
+            echo: None,
+        }
+    }
+
+    pub fn msg_type(&self) -> icmpv4::MsgType {
+        self.msg_type
+    }
+
+    /// The size of everything in the message which comes before the payload
+    fn header_len(&self) -> usize {
+        match self.echo {
+            Some(_) => icmpv4::HEADER_SIZE + icmpv4::ECHO_HEADER_SIZE,
+            // Unused or type specific word which precedes the payload
+            None => icmpv4::HEADER_SIZE + 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Transport {
+    Icmpv4(Icmpv4Message),
+    Udp { src_port: u16, dst_port: u16 },
+    Raw(ipv4::ProtocolType),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DatagramBuilder {
+    ip: Ipv4Builder,
+    transport: Transport,
+}
+
+impl DatagramBuilder {
+    /// Pushes every header in front of the packet's data, which becomes the payload.
+    pub fn finalize<const SIZE: usize>(self, packet: &mut Packet<'_, '_, SIZE>) -> Result<()> {
+        let proto = match self.transport {
+            Transport::Icmpv4(message) => {
+                let buf = push(packet, Layer::Icmpv4, message.header_len())?;
+                buf.fill(0);
+                if let Some(echo) = message.echo {
+                    echo.encode(&mut buf[icmpv4::HEADER_SIZE..])?;
+                }
+                let mut icmp_hdr = icmpv4::Header {
+                    msg_type: message.msg_type,
+                    code: message.code,
+                    checksum: 0,
+                };
+                icmp_hdr.encode(packet.as_mut_slice())?;
+                icmp_hdr.checksum = util::checksum(packet.as_slice());
+                icmp_hdr.encode(packet.as_mut_slice())?;
+                ipv4::ProtocolType::IcmpV4
+            }
+            Transport::Udp { src_port, dst_port } => {
+                push(packet, Layer::Udp, udp::HEADER_SIZE)?;
+                let mut udp_hdr = udp::Header {
+                    src_port,
+                    dst_port,
+                    length: packet.len() as u16,
+                    checksum: 0,
+                };
+                udp_hdr.encode(packet.as_mut_slice())?;
+                udp_hdr.checksum =
+                    udp::checksum(self.ip.src_addr, self.ip.dst_addr, packet.as_slice());
+                udp_hdr.encode(packet.as_mut_slice())?;
+                ipv4::ProtocolType::Udp
+            }
+            Transport::Raw(proto) => proto,
+        };
+
+        let header_len = match self.ip.router_alert {
+            true => ipv4::HEADER_SIZE + ROUTER_ALERT_OPTION.len(),
+            false => ipv4::HEADER_SIZE,
+        };
+        push(packet, Layer::Ipv4, header_len)?;
+        let mut ip_hdr = ipv4::Header {
+            version: ipv4::VERSION,
+            internet_header_len: (header_len / 4) as u8,
+            type_of_service: self.ip.type_of_service,
+            datagram_len: packet.len() as u16,
+            id: self.ip.id,
+            control_flags: self.ip.control_flags,
+            fragment_offset: 0,
+            time_to_live: self.ip.time_to_live,
+            proto,
+            checksum: 0,
+            src_addr: self.ip.src_addr,
+            dst_addr: self.ip.dst_addr,
+        };
+        ip_hdr.encode(packet.as_mut_slice())?;
+        if self.ip.router_alert {
+            packet.as_mut_slice()[ipv4::HEADER_SIZE..header_len]
+                .copy_from_slice(&ROUTER_ALERT_OPTION);
+        }
+        ip_hdr.checksum = util::checksum(&packet.as_slice()[..header_len]);
+        ip_hdr.encode(packet.as_mut_slice())?;
+
+        if let Some(ethernet) = self.ip.ethernet {
+            let eth_hdr = eth::Header {
+                dmac: ethernet.dmac,
+                smac: ethernet.smac,
+                ethertype: eth::Ethertype::IPv4,
+            };
+            eth_hdr.encode(push(packet, Layer::Ethernet, eth::HEADER_SIZE)?)?;
+        }
+        Ok(())
+    }
+}
+
+fn push<'a, const SIZE: usize>(
+    packet: &'a mut Packet<'_, '_, SIZE>,
+    layer: Layer,
+    len: usize,
+) -> Result<&'a mut [u8]> {
+    let available = packet.headroom();
+    packet
+        .push_header(len)
+        .map_err(|_| NettyError::BufferTooSmall {
+            layer,
+            available,
+            needed: len,
+        })
+}
+
+mod tests {
+    #[test]
+    fn builds_udp_datagram() {
+        use crate::builder::*;
+        use crate::packet_pool::*;
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE]));
+        let pool = PacketPool::<1>::new(buf).unwrap();
+        let mut packet = pool.allocate().unwrap();
+        packet.put(3).unwrap().copy_from_slice(b"abc");
+        PacketBuilder::ethernet([1; 6], [2; 6])
+            .ipv4(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1))
+            .udp(1000, 2000)
+            .finalize(&mut packet)
+            .unwrap();
+
+        let (_, datagram) = eth::Header::decode(packet.as_slice()).unwrap();
+        let (ip_hdr, segment) = ipv4::Header::decode(datagram).unwrap();
+        assert_eq!(ip_hdr.version, 4);
+        assert_eq!(ip_hdr.internet_header_len, 5);
+        assert_eq!(ip_hdr.datagram_len as usize, datagram.len());
+        assert_eq!(util::checksum(&datagram[..ipv4::HEADER_SIZE]), 0);
+        let (udp_hdr, payload) = udp::Header::decode(segment).unwrap();
+        assert_eq!(udp_hdr.length, 11);
+        assert_eq!(payload, b"abc");
+        assert!(udp::UdpPacket::new_checked(segment)
+            .unwrap()
+            .verify_checksum(ip_hdr.src_addr, ip_hdr.dst_addr));
+        packet.discard();
+    }
+}
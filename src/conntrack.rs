@@ -0,0 +1,308 @@
+use crate::{icmpv4, ipv4};
+use byteorder::{ByteOrder, NetworkEndian};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The most flows tracked at once. Datagrams which would start a new flow while the
+/// table is full are `ConnState::Invalid`.
+pub const CONNTRACK_ENTRIES: usize = 256;
+/// How long an ICMP echo flow is kept after its last datagram
+pub const ICMP_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a UDP flow which hasn't been answered is kept after its last datagram
+pub const UDP_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a UDP flow which has seen traffic both ways is kept after its last datagram
+pub const UDP_STREAM_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// How a datagram relates to the flows the stack has seen
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ConnState {
+    /// It starts a flow, or belongs to one which hasn't been answered yet
+    New,
+    /// It belongs to a flow which has seen traffic both ways
+    Established,
+    /// It's an ICMP error about a datagram of a known flow
+    Related,
+    /// It can't start a flow and doesn't belong to one, e.g. an unexpected echo reply
+    Invalid,
+}
+
+/// Identifies a flow as seen from the host which started it. For ICMP echo flows both
+/// ports are the echo identifier.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub protocol: u8,
+    pub src_addr: Ipv4Addr,
+    pub dst_addr: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+impl FlowKey {
+    /// The key of datagrams going the other way
+    pub fn reversed(&self) -> Self {
+        Self {
+            protocol: self.protocol,
+            src_addr: self.dst_addr,
+            dst_addr: self.src_addr,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+        }
+    }
+}
+
+/// A flow as seen by `ConnTrack::connections`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Connection {
+    pub key: FlowKey,
+    /// Whether a datagram has been seen going back to the host which started the flow
+    pub established: bool,
+    /// Time until the flow is forgotten unless more of its datagrams are seen
+    pub expires_in: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Flow {
+    key: FlowKey,
+    established: bool,
+    expires_at: Instant,
+}
+
+impl Flow {
+    fn refresh(&mut self, now: Instant) {
+        let timeout = match (self.key.protocol, self.established) {
+            (proto, _) if proto == ipv4::ProtocolType::IcmpV4 as u8 => ICMP_TIMEOUT,
+            (_, true) => UDP_STREAM_TIMEOUT,
+            (_, false) => UDP_TIMEOUT,
+        };
+        self.expires_at = now + timeout;
+    }
+}
+
+/// What a datagram means to the flow table
+enum Tracked {
+    /// A datagram which may start a flow
+    Starts(FlowKey),
+    /// A datagram which only makes sense as an answer, such as an echo reply
+    Answers(FlowKey),
+    /// An ICMP error quoting a datagram of the flow
+    Error(FlowKey),
+}
+
+/// Follows the flows going through the stack so packet filter rules can tell answers to
+/// traffic the stack sent from traffic nobody asked for. It's shared with the
+/// application through an `Arc` so flows can be inspected while the stack is running.
+pub struct ConnTrack {
+    flows: Mutex<Vec<Flow>>,
+}
+
+impl Default for ConnTrack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnTrack {
+    pub fn new() -> Self {
+        Self {
+            flows: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Every flow being tracked, oldest first
+    pub fn connections(&self) -> Vec<Connection> {
+        let now = Instant::now();
+        self.flows
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|flow| Connection {
+                key: flow.key,
+                established: flow.established,
+                expires_in: flow.expires_at.saturating_duration_since(now),
+            })
+            .collect()
+    }
+
+    /// Forgets every flow
+    pub fn flush(&self) {
+        self.flows.lock().unwrap().clear();
+    }
+
+    /// Says how an IPv4 datagram relates to earlier traffic without recording it, so a
+    /// filter can judge it first. Datagrams of protocols which aren't tracked give `None`.
+    pub(crate) fn lookup(&self, datagram: &[u8]) -> Option<ConnState> {
+        self.follow(datagram, false)
+    }
+
+    /// Records an IPv4 datagram the stack has let through, starting or refreshing its
+    /// flow, and says how it relates to earlier traffic
+    pub(crate) fn track(&self, datagram: &[u8]) -> Option<ConnState> {
+        self.follow(datagram, true)
+    }
+
+    fn follow(&self, datagram: &[u8], record: bool) -> Option<ConnState> {
+        let tracked = classify(datagram)?;
+        let now = Instant::now();
+        let mut flows = self.flows.lock().unwrap();
+        let key = match tracked {
+            Tracked::Error(quoted) => {
+                let known = flows
+                    .iter()
+                    .any(|flow| flow.key == quoted || flow.key == quoted.reversed());
+                return Some(match known {
+                    true => ConnState::Related,
+                    false => ConnState::Invalid,
+                });
+            }
+            Tracked::Starts(key) | Tracked::Answers(key) => key,
+        };
+
+        if let Some(flow) = flows.iter_mut().find(|flow| flow.key == key) {
+            if record {
+                flow.refresh(now);
+            }
+            return Some(match flow.established {
+                true => ConnState::Established,
+                false => ConnState::New,
+            });
+        }
+        if let Some(flow) = flows.iter_mut().find(|flow| flow.key == key.reversed()) {
+            if record {
+                flow.established = true;
+                flow.refresh(now);
+            }
+            return Some(ConnState::Established);
+        }
+        if let Tracked::Answers(_) = tracked {
+            return Some(ConnState::Invalid);
+        }
+        if flows.len() >= CONNTRACK_ENTRIES {
+            log::warn!("Connection tracking table is full!");
+            return Some(ConnState::Invalid);
+        }
+        if !record {
+            return Some(ConnState::New);
+        }
+        let mut flow = Flow {
+            key,
+            established: false,
+            expires_at: now,
+        };
+        flow.refresh(now);
+        flows.push(flow);
+        Some(ConnState::New)
+    }
+
+    /// Forgets flows which haven't seen a datagram within their timeout
+    pub(crate) fn expire(&self) {
+        let now = Instant::now();
+        self.flows
+            .lock()
+            .unwrap()
+            .retain(|flow| flow.expires_at > now);
+    }
+}
+
+/// Works out which flow a datagram belongs to. This also reads datagrams quoted in ICMP
+/// errors, which may have been cut short after the first eight bytes of their payload.
+fn classify(datagram: &[u8]) -> Option<Tracked> {
+    let (key, payload) = read_ports(datagram)?;
+    if key.protocol == ipv4::ProtocolType::Udp as u8 {
+        return Some(Tracked::Starts(key));
+    }
+    if key.protocol != ipv4::ProtocolType::IcmpV4 as u8 {
+        return None;
+    }
+    let msg_type = *payload.first()?;
+    match msg_type {
+        t if t == icmpv4::MsgType::EchoRequest as u8 => Some(Tracked::Starts(key)),
+        t if t == icmpv4::MsgType::EchoReply as u8 => Some(Tracked::Answers(key)),
+        t if icmpv4::is_error(t) => {
+            let quoted = payload.get(icmpv4::HEADER_SIZE + 4..)?;
+            let (quoted_key, _) = read_ports(quoted)?;
+            Some(Tracked::Error(quoted_key))
+        }
+        _ => None,
+    }
+}
+
+/// Reads the flow key of a datagram, and the part of its payload which is present
+pub(crate) fn read_ports(datagram: &[u8]) -> Option<(FlowKey, &[u8])> {
+    if datagram.len() < ipv4::HEADER_SIZE {
+        return None;
+    }
+    let ip_packet = ipv4::Ipv4Packet::new_unchecked(datagram);
+    let header_len = ip_packet.header_len();
+    // Only the first fragment holds the ports
+    if ip_packet.version() != ipv4::VERSION
+        || header_len < ipv4::HEADER_SIZE
+        || ip_packet.fragment_offset() != 0
+    {
+        return None;
+    }
+    let end = std::cmp::min(datagram.len(), ip_packet.total_len() as usize);
+    let payload = datagram.get(header_len..end)?;
+    let protocol = ip_packet.protocol();
+    let (src_port, dst_port) = if protocol == ipv4::ProtocolType::IcmpV4 as u8 {
+        // Only echo messages have an identifier, but errors don't need one
+        let id = payload.get(4..6).map_or(0, NetworkEndian::read_u16);
+        (id, id)
+    } else if protocol == ipv4::ProtocolType::Udp as u8 {
+        let ports = payload.get(0..4)?;
+        (
+            NetworkEndian::read_u16(&ports[0..2]),
+            NetworkEndian::read_u16(&ports[2..4]),
+        )
+    } else {
+        (0, 0)
+    };
+    let key = FlowKey {
+        protocol,
+        src_addr: ip_packet.src_addr(),
+        dst_addr: ip_packet.dst_addr(),
+        src_port,
+        dst_port,
+    };
+    Some((key, payload))
+}
+
+mod tests {
+    #[test]
+    fn follows_echo_flows() {
+        use crate::conntrack::*;
+        use crate::nat::test_datagram;
+        use crate::{Icmpv4Message, PacketBuilder};
+        let us = Ipv4Addr::new(10, 0, 0, 2);
+        let host = Ipv4Addr::new(10, 0, 0, 1);
+        let datagram = |src, dst, message, payload: &[u8]| {
+            test_datagram(PacketBuilder::ipv4(src, dst).icmpv4(message), payload)
+        };
+
+        let conntrack = ConnTrack::new();
+        let request = datagram(us, host, Icmpv4Message::echo_request(5, 1), b"");
+        let reply = datagram(host, us, Icmpv4Message::echo_reply(5, 1), b"");
+        let stray_reply = datagram(host, us, Icmpv4Message::echo_reply(6, 1), b"");
+        let error = datagram(
+            host,
+            us,
+            Icmpv4Message::destination_unreachable(1),
+            &request[..ipv4::HEADER_SIZE + 8],
+        );
+        assert_eq!(conntrack.track(&stray_reply), Some(ConnState::Invalid));
+        assert_eq!(conntrack.track(&error), Some(ConnState::Invalid));
+        assert_eq!(conntrack.track(&request), Some(ConnState::New));
+        assert_eq!(conntrack.track(&request), Some(ConnState::New));
+        assert_eq!(conntrack.track(&error), Some(ConnState::Related));
+        assert_eq!(conntrack.track(&reply), Some(ConnState::Established));
+        assert_eq!(conntrack.track(&request), Some(ConnState::Established));
+
+        let connections = conntrack.connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].key.src_addr, us);
+        assert_eq!(connections[0].key.src_port, 5);
+        assert!(connections[0].established);
+        conntrack.flush();
+        assert!(conntrack.connections().is_empty());
+    }
+}
@@ -0,0 +1,108 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+
+/// Anything the stack can send and receive Ethernet frames through. Each read must return
+/// exactly one frame and each write is given exactly one frame, as a TAP device does. A
+/// read of zero bytes means the device has gone away.
+pub trait Device: AsyncRead + AsyncWrite {}
+
+impl<T: AsyncRead + AsyncWrite> Device for T {}
+
+/// A device which passes frames to and from a `MemoryDeviceHandle` instead of a network,
+/// for driving the stack from tests and fuzzers.
+pub struct MemoryDevice {
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+/// The other end of a `MemoryDevice`. Frames injected here are read by the stack, and
+/// frames written by the stack can be received here.
+pub struct MemoryDeviceHandle {
+    tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl MemoryDevice {
+    pub fn new() -> (Self, MemoryDeviceHandle) {
+        let (inject_tx, inject_rx) = mpsc::unbounded_channel();
+        let (output_tx, output_rx) = mpsc::unbounded_channel();
+        let device = Self {
+            rx: inject_rx,
+            tx: output_tx,
+        };
+        let handle = MemoryDeviceHandle {
+            tx: Some(inject_tx),
+            rx: output_rx,
+        };
+        (device, handle)
+    }
+}
+
+impl MemoryDeviceHandle {
+    /// Queues a frame to be read by the stack. Empty frames are ignored, as reading one
+    /// would look like the device closing.
+    pub fn inject(&self, frame: &[u8]) {
+        if let (Some(tx), false) = (&self.tx, frame.is_empty()) {
+            // The stack may have stopped, in which case nobody is left to read it
+            let _ = tx.send(frame.to_vec());
+        }
+    }
+
+    /// Stops feeding the stack. Once it has read every injected frame, the stack sees the
+    /// device close and `NettyStack::run` returns.
+    pub fn close(&mut self) {
+        self.tx = None;
+    }
+
+    /// Waits for the next frame written by the stack, or `None` once the device is gone
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.rx.recv().await
+    }
+
+    /// Takes the next frame written by the stack if there is one
+    pub fn try_recv(&mut self) -> Option<Vec<u8>> {
+        self.rx.try_recv().ok()
+    }
+}
+
+impl AsyncRead for MemoryDevice {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(frame)) => {
+                // Like a real device, a frame too big for the buffer is cut short
+                let len = std::cmp::min(frame.len(), buf.remaining());
+                buf.put_slice(&frame[..len]);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(None) => Poll::Ready(Ok(())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for MemoryDevice {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // Frames written after the handle is dropped are lost, as they would be on a wire
+        let _ = self.tx.send(buf.to_vec());
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
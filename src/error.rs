@@ -0,0 +1,154 @@
+use crate::stats::DropReason;
+use std::fmt;
+use std::io;
+use std::net::Ipv4Addr;
+
+pub type Result<T> = std::result::Result<T, NettyError>;
+
+/// The protocol layer an error was raised from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layer {
+    Ethernet,
+    Arp,
+    Ipv4,
+    Icmpv4,
+    Igmp,
+    Udp,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Layer::Ethernet => "ethernet",
+            Layer::Arp => "arp",
+            Layer::Ipv4 => "ipv4",
+            Layer::Icmpv4 => "icmpv4",
+            Layer::Igmp => "igmp",
+            Layer::Udp => "udp",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug)]
+pub enum NettyError {
+    /// The buffer held only `available` of the `needed` bytes of a header
+    Truncated {
+        layer: Layer,
+        available: usize,
+        needed: usize,
+    },
+    /// A header field held a value the decoder doesn't understand
+    UnknownValue {
+        layer: Layer,
+        field: &'static str,
+        value: u32,
+    },
+    /// A header or payload checksum didn't match the data it covers
+    BadChecksum { layer: Layer },
+    /// The buffer given to an encoder can't fit the header being written
+    BufferTooSmall {
+        layer: Layer,
+        available: usize,
+        needed: usize,
+    },
+    /// There's no ARP entry for the next hop of an outgoing packet
+    ArpMiss(Ipv4Addr),
+    /// There was no free slot in the packet pool
+    PoolExhausted,
+    /// No RARP server answered with an address for this device
+    NoRarpReply,
+    /// The device failed to read or write a frame
+    Io(io::Error),
+}
+
+impl NettyError {
+    /// The reason to count for a frame which was dropped because of this error, if any.
+    pub fn drop_reason(&self) -> Option<DropReason> {
+        match self {
+            NettyError::Truncated { .. } | NettyError::UnknownValue { .. } => {
+                Some(DropReason::DecodeError)
+            }
+            NettyError::BadChecksum { .. } => Some(DropReason::BadChecksum),
+            NettyError::ArpMiss(_) => Some(DropReason::ArpMiss),
+            NettyError::PoolExhausted => Some(DropReason::PoolExhausted),
+            NettyError::BufferTooSmall { .. } | NettyError::NoRarpReply | NettyError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for NettyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NettyError::Truncated {
+                layer,
+                available,
+                needed,
+            } => write!(
+                f,
+                "{}: truncated header, {} of {} bytes",
+                layer, available, needed
+            ),
+            NettyError::UnknownValue {
+                layer,
+                field,
+                value,
+            } => write!(f, "{}: unknown {} {:#x}", layer, field, value),
+            NettyError::BadChecksum { layer } => write!(f, "{}: bad checksum", layer),
+            NettyError::BufferTooSmall {
+                layer,
+                available,
+                needed,
+            } => write!(
+                f,
+                "{}: buffer of {} bytes can't fit {} bytes",
+                layer, available, needed
+            ),
+            NettyError::ArpMiss(addr) => write!(f, "no ARP entry for {}", addr),
+            NettyError::PoolExhausted => write!(f, "packet pool exhausted"),
+            NettyError::NoRarpReply => write!(f, "no RARP reply"),
+            NettyError::Io(err) => write!(f, "device error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for NettyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NettyError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NettyError {
+    fn from(err: io::Error) -> Self {
+        NettyError::Io(err)
+    }
+}
+
+/// Checks that `buf` holds at least `needed` bytes before a header is decoded from it
+pub(crate) fn check_decode_len(layer: Layer, buf: &[u8], needed: usize) -> Result<()> {
+    if buf.len() < needed {
+        Err(NettyError::Truncated {
+            layer,
+            available: buf.len(),
+            needed,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks that `buf` has room for `needed` bytes before a header is encoded into it
+pub(crate) fn check_encode_len(layer: Layer, buf: &[u8], needed: usize) -> Result<()> {
+    if buf.len() < needed {
+        Err(NettyError::BufferTooSmall {
+            layer,
+            available: buf.len(),
+            needed,
+        })
+    } else {
+        Ok(())
+    }
+}
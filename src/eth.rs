@@ -1,13 +1,14 @@
-use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use crate::error::{check_decode_len, check_encode_len, Layer, NettyError, Result};
+use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt, WriteBytesExt};
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
-use std::io;
 use std::io::{Read, Write};
+use std::net::Ipv4Addr;
 
 pub const HEADER_SIZE: usize = 14;
 
 #[repr(u16)]
-#[derive(Clone, Copy, Debug, FromPrimitive, ToPrimitive)]
+#[derive(Clone, Copy, Debug, PartialEq, FromPrimitive, ToPrimitive)]
 pub enum Ethertype {
     IPv4 = 0x0800,
     ARP = 0x0806,
@@ -22,7 +23,8 @@ pub struct Header {
 }
 
 impl Header {
-    pub fn decode(buf: &[u8]) -> io::Result<(Self, &[u8])> {
+    pub fn decode(buf: &[u8]) -> Result<(Self, &[u8])> {
+        check_decode_len(Layer::Ethernet, buf, HEADER_SIZE)?;
         let mut cursor = std::io::Cursor::new(buf);
         let mut dmac = [0u8; 6];
         cursor.read_exact(&mut dmac)?;
@@ -31,7 +33,13 @@ impl Header {
         let raw_ethertype = cursor.read_u16::<NetworkEndian>()?;
         let ethertype = match FromPrimitive::from_u16(raw_ethertype) {
             Some(ethertype) => ethertype,
-            None => return Err(io::ErrorKind::Unsupported.into()),
+            None => {
+                return Err(NettyError::UnknownValue {
+                    layer: Layer::Ethernet,
+                    field: "ethertype",
+                    value: raw_ethertype.into(),
+                })
+            }
         };
         Ok((
             Header {
@@ -43,7 +51,8 @@ impl Header {
         ))
     }
 
-    pub fn encode(self, buf: &mut [u8]) -> io::Result<usize> {
+    pub fn encode(self, buf: &mut [u8]) -> Result<usize> {
+        check_encode_len(Layer::Ethernet, buf, HEADER_SIZE)?;
         let mut cursor = std::io::Cursor::new(buf);
         cursor.write_all(&self.dmac)?;
         cursor.write_all(&self.smac)?;
@@ -51,3 +60,87 @@ impl Header {
         Ok(HEADER_SIZE)
     }
 }
+
+/// The Ethernet address IPv4 multicast datagrams for `group` are sent to: 01:00:5e
+/// followed by the low 23 bits of the group address (RFC 1112)
+pub fn multicast_mac(group: Ipv4Addr) -> [u8; 6] {
+    let octets = group.octets();
+    [0x01, 0x00, 0x5e, octets[1] & 0x7f, octets[2], octets[3]]
+}
+
+/// A view of an Ethernet frame which reads and writes the header fields directly in
+/// `buffer`. The length is checked once in `new_checked`, so the accessors can't fail.
+#[derive(Clone, Copy, Debug)]
+pub struct EthernetFrame<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> EthernetFrame<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    pub fn new_checked(buffer: T) -> Result<Self> {
+        check_decode_len(Layer::Ethernet, buffer.as_ref(), HEADER_SIZE)?;
+        Ok(Self { buffer })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    pub fn dmac(&self) -> [u8; 6] {
+        self.buffer.as_ref()[0..6].try_into().unwrap()
+    }
+
+    pub fn smac(&self) -> [u8; 6] {
+        self.buffer.as_ref()[6..12].try_into().unwrap()
+    }
+
+    pub fn ethertype(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[12..14])
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[HEADER_SIZE..]
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> EthernetFrame<T> {
+    pub fn set_dmac(&mut self, dmac: [u8; 6]) {
+        self.buffer.as_mut()[0..6].copy_from_slice(&dmac);
+    }
+
+    pub fn set_smac(&mut self, smac: [u8; 6]) {
+        self.buffer.as_mut()[6..12].copy_from_slice(&smac);
+    }
+
+    pub fn set_ethertype(&mut self, ethertype: u16) {
+        NetworkEndian::write_u16(&mut self.buffer.as_mut()[12..14], ethertype);
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer.as_mut()[HEADER_SIZE..]
+    }
+}
+
+mod tests {
+    #[test]
+    fn decode_encode_round_trip() {
+        use crate::eth::*;
+        let mut rng = crate::util::Rng::new(1);
+        for _ in 0..1000 {
+            let mut buf = [0u8; HEADER_SIZE];
+            buf.iter_mut().for_each(|byte| *byte = rng.next_u64() as u8);
+            // Mostly use known ethertypes so that the decode usually succeeds
+            let ethertype =
+                [0x0800, 0x0806, 0x8035, rng.next_u64() as u16][rng.next_u64() as usize % 4];
+            buf[12..14].copy_from_slice(&ethertype.to_be_bytes());
+            if let Ok((header, _)) = Header::decode(&buf) {
+                let mut encoded = [0u8; HEADER_SIZE];
+                header.encode(&mut encoded).unwrap();
+                assert_eq!(encoded, buf);
+            }
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use crate::conntrack::ConnState;
+use crate::ipv4::{self, Ipv4Cidr};
+use crate::{eth, icmpv4};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+/// The points in the stack where frames are checked against rules
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Chain {
+    /// Every frame received from the device, before its destination MAC address is checked
+    Ingress,
+    /// IPv4 datagrams which are about to be handled by the stack or an application
+    Input,
+    /// Every frame about to be written to the device
+    Output,
+}
+
+impl Chain {
+    fn index(self) -> usize {
+        match self {
+            Chain::Ingress => 0,
+            Chain::Input => 1,
+            Chain::Output => 2,
+        }
+    }
+}
+
+/// What happens to a frame which matches a rule
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Stop checking rules and let the frame through
+    Accept,
+    /// Stop checking rules and throw the frame away
+    Drop,
+    /// Throw the frame away and tell its sender with an ICMP administratively prohibited
+    /// error. Frames which aren't IPv4, and any frame at `Chain::Output`, are just dropped.
+    Reject,
+    /// Count the frame in the rule's counters and carry on with the next rule
+    Count,
+}
+
+/// What the stack does with a frame once a chain has been checked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Accept,
+    Drop,
+    Reject,
+}
+
+/// A rule matches frames for which every field that's set matches. Fields about the
+/// IPv4 header never match frames which aren't IPv4, and likewise for the ICMP type.
+/// Connection states are only known at `Chain::Input` and `Chain::Output`, for the
+/// protocols the connection tracker follows.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub action: Action,
+    pub interface: Option<String>,
+    pub smac: Option<[u8; 6]>,
+    pub dmac: Option<[u8; 6]>,
+    pub ethertype: Option<u16>,
+    pub src: Option<Ipv4Cidr>,
+    pub dst: Option<Ipv4Cidr>,
+    pub protocol: Option<u8>,
+    pub icmp_type: Option<u8>,
+    pub state: Option<ConnState>,
+}
+
+impl Rule {
+    /// A rule which matches every frame
+    pub fn new(action: Action) -> Self {
+        Self {
+            action,
+            interface: None,
+            smac: None,
+            dmac: None,
+            ethertype: None,
+            src: None,
+            dst: None,
+            protocol: None,
+            icmp_type: None,
+            state: None,
+        }
+    }
+
+    pub fn interface(mut self, name: &str) -> Self {
+        self.interface = Some(name.to_string());
+        self
+    }
+
+    pub fn smac(mut self, smac: [u8; 6]) -> Self {
+        self.smac = Some(smac);
+        self
+    }
+
+    pub fn dmac(mut self, dmac: [u8; 6]) -> Self {
+        self.dmac = Some(dmac);
+        self
+    }
+
+    pub fn ethertype(mut self, ethertype: eth::Ethertype) -> Self {
+        self.ethertype = Some(ethertype as u16);
+        self
+    }
+
+    pub fn src(mut self, prefix: Ipv4Cidr) -> Self {
+        self.src = Some(prefix);
+        self
+    }
+
+    pub fn dst(mut self, prefix: Ipv4Cidr) -> Self {
+        self.dst = Some(prefix);
+        self
+    }
+
+    pub fn protocol(mut self, protocol: ipv4::ProtocolType) -> Self {
+        self.protocol = Some(protocol as u8);
+        self
+    }
+
+    pub fn icmp_type(mut self, msg_type: icmpv4::MsgType) -> Self {
+        self.protocol = Some(ipv4::ProtocolType::IcmpV4 as u8);
+        self.icmp_type = Some(msg_type as u8);
+        self
+    }
+
+    pub fn state(mut self, state: ConnState) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    fn matches(&self, interface: &str, frame: &FrameInfo) -> bool {
+        fn check<T: PartialEq>(want: Option<T>, got: Option<T>) -> bool {
+            want.is_none() || want == got
+        }
+        fn contains(prefix: Option<Ipv4Cidr>, addr: Option<Ipv4Addr>) -> bool {
+            match (prefix, addr) {
+                (None, _) => true,
+                (Some(prefix), Some(addr)) => prefix.contains(addr),
+                (Some(_), None) => false,
+            }
+        }
+        check(self.interface.as_deref(), Some(interface))
+            && check(self.smac, frame.smac)
+            && check(self.dmac, frame.dmac)
+            && check(self.ethertype, frame.ethertype)
+            && contains(self.src, frame.src_addr)
+            && contains(self.dst, frame.dst_addr)
+            && check(self.protocol, frame.protocol)
+            && check(self.icmp_type, frame.icmp_type)
+            && check(self.state, frame.conn_state)
+    }
+}
+
+/// A rule as seen by `PacketFilter::rules`, with how much traffic it has matched
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleEntry {
+    pub rule: Rule,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// The header fields rules can match on, read once from a frame. Fields are `None` when
+/// the frame doesn't have the header they come from.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct FrameInfo {
+    pub len: usize,
+    pub smac: Option<[u8; 6]>,
+    pub dmac: Option<[u8; 6]>,
+    pub ethertype: Option<u16>,
+    pub src_addr: Option<Ipv4Addr>,
+    pub dst_addr: Option<Ipv4Addr>,
+    pub protocol: Option<u8>,
+    pub icmp_type: Option<u8>,
+    pub conn_state: Option<ConnState>,
+}
+
+impl FrameInfo {
+    /// Reads a frame starting at its Ethernet header
+    pub fn from_frame(frame: &[u8]) -> Self {
+        let info = Self::from_link_header(frame);
+        match info.ethertype {
+            Some(ethertype) if ethertype == eth::Ethertype::IPv4 as u16 => {
+                info.with_datagram(&frame[eth::HEADER_SIZE..])
+            }
+            _ => info,
+        }
+    }
+
+    /// Reads only the Ethernet header of a frame, leaving the datagram for later
+    pub fn from_link_header(frame: &[u8]) -> Self {
+        let mut info = Self {
+            len: frame.len(),
+            ..Self::default()
+        };
+        if let Ok(eth_frame) = eth::EthernetFrame::new_checked(frame) {
+            info.smac = Some(eth_frame.smac());
+            info.dmac = Some(eth_frame.dmac());
+            info.ethertype = Some(eth_frame.ethertype());
+        }
+        info
+    }
+
+    /// Adds the fields of the IPv4 datagram which followed the Ethernet header
+    pub fn with_datagram(self, datagram: &[u8]) -> Self {
+        Self {
+            len: self.len,
+            smac: self.smac,
+            dmac: self.dmac,
+            ethertype: self.ethertype,
+            ..Self::from_datagram(datagram)
+        }
+    }
+
+    /// Reads an IPv4 datagram whose link layer header is gone
+    pub fn from_datagram(datagram: &[u8]) -> Self {
+        let mut info = Self {
+            len: datagram.len(),
+            ..Self::default()
+        };
+        let ip_packet = match ipv4::Ipv4Packet::new_checked(datagram) {
+            Ok(ip_packet) => ip_packet,
+            _ => return info,
+        };
+        info.src_addr = Some(ip_packet.src_addr());
+        info.dst_addr = Some(ip_packet.dst_addr());
+        info.protocol = Some(ip_packet.protocol());
+        // Only the first fragment holds the ICMP header
+        if ip_packet.protocol() == ipv4::ProtocolType::IcmpV4 as u8
+            && ip_packet.fragment_offset() == 0
+        {
+            info.icmp_type = ip_packet.payload().first().copied();
+        }
+        info
+    }
+}
+
+/// Ordered rule chains checked by the stack, shared with the application through an
+/// `Arc` so rules can be changed while the stack is running. A frame which gets to the
+/// end of a chain without an `Accept`, `Drop` or `Reject` rule matching is accepted.
+pub struct PacketFilter {
+    chains: Mutex<[Vec<RuleEntry>; 3]>,
+}
+
+impl Default for PacketFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PacketFilter {
+    pub fn new() -> Self {
+        Self {
+            chains: Mutex::new(Default::default()),
+        }
+    }
+
+    /// Adds `rule` to the end of `chain`
+    pub fn append(&self, chain: Chain, rule: Rule) {
+        self.chains.lock().unwrap()[chain.index()].push(RuleEntry {
+            rule,
+            packets: 0,
+            bytes: 0,
+        });
+    }
+
+    /// Adds `rule` at `index` in `chain`, moving the rules after it down. Returns false
+    /// if `index` is past the end of the chain.
+    pub fn insert(&self, chain: Chain, index: usize, rule: Rule) -> bool {
+        let mut chains = self.chains.lock().unwrap();
+        let rules = &mut chains[chain.index()];
+        if index > rules.len() {
+            return false;
+        }
+        let entry = RuleEntry {
+            rule,
+            packets: 0,
+            bytes: 0,
+        };
+        rules.insert(index, entry);
+        true
+    }
+
+    pub fn remove(&self, chain: Chain, index: usize) -> Option<Rule> {
+        let mut chains = self.chains.lock().unwrap();
+        let rules = &mut chains[chain.index()];
+        if index >= rules.len() {
+            return None;
+        }
+        Some(rules.remove(index).rule)
+    }
+
+    /// Removes every rule from `chain`
+    pub fn flush(&self, chain: Chain) {
+        self.chains.lock().unwrap()[chain.index()].clear();
+    }
+
+    /// The rules in `chain`, in the order they're checked
+    pub fn rules(&self, chain: Chain) -> Vec<RuleEntry> {
+        self.chains.lock().unwrap()[chain.index()].clone()
+    }
+
+    /// Checks a frame on `interface` against the rules in `chain`
+    pub(crate) fn check(&self, chain: Chain, interface: &str, frame: &FrameInfo) -> Verdict {
+        let mut chains = self.chains.lock().unwrap();
+        for entry in chains[chain.index()].iter_mut() {
+            if !entry.rule.matches(interface, frame) {
+                continue;
+            }
+            entry.packets += 1;
+            entry.bytes += frame.len as u64;
+            match entry.rule.action {
+                Action::Accept => return Verdict::Accept,
+                Action::Drop => return Verdict::Drop,
+                Action::Reject => return Verdict::Reject,
+                Action::Count => {}
+            }
+        }
+        Verdict::Accept
+    }
+
+    /// Whether `chain` has no rules, so frames needn't be read to check them
+    pub(crate) fn is_empty(&self, chain: Chain) -> bool {
+        self.chains.lock().unwrap()[chain.index()].is_empty()
+    }
+}
+
+mod tests {
+    #[test]
+    fn rules_match_in_order() {
+        use crate::filter::*;
+        let filter = PacketFilter::new();
+        let lan = Ipv4Cidr::new(Ipv4Addr::new(10, 0, 0, 0), 24);
+        filter.append(Chain::Input, Rule::new(Action::Count).src(lan));
+        filter.append(
+            Chain::Input,
+            Rule::new(Action::Reject).icmp_type(icmpv4::MsgType::EchoRequest),
+        );
+        assert!(filter.insert(Chain::Input, 0, Rule::new(Action::Accept).interface("lo")));
+        assert!(!filter.insert(Chain::Input, 4, Rule::new(Action::Drop)));
+
+        let mut datagram = [0u8; 28];
+        datagram[0] = 0x45;
+        let mut ip_packet = ipv4::Ipv4Packet::new_unchecked(&mut datagram[..]);
+        ip_packet.set_total_len(28);
+        ip_packet.set_protocol(ipv4::ProtocolType::IcmpV4 as u8);
+        ip_packet.set_src_addr(Ipv4Addr::new(10, 0, 0, 1));
+        datagram[ipv4::HEADER_SIZE] = icmpv4::MsgType::EchoRequest as u8;
+        let ping = FrameInfo::from_datagram(&datagram);
+        assert_eq!(filter.check(Chain::Input, "lo", &ping), Verdict::Accept);
+        assert_eq!(filter.check(Chain::Input, "tap0", &ping), Verdict::Reject);
+        assert_eq!(filter.check(Chain::Output, "tap0", &ping), Verdict::Accept);
+
+        let rules = filter.rules(Chain::Input);
+        assert_eq!(
+            rules.iter().map(|r| r.packets).collect::<Vec<_>>(),
+            [1, 1, 1]
+        );
+        assert_eq!(rules[1].bytes, 28);
+        assert_eq!(
+            filter.remove(Chain::Input, 0).unwrap().action,
+            Action::Accept
+        );
+        filter.flush(Chain::Input);
+        assert!(filter.is_empty(Chain::Input));
+    }
+}
@@ -1,10 +1,15 @@
-use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use crate::error::{check_decode_len, check_encode_len, Layer, NettyError, Result};
+use crate::util;
+use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt, WriteBytesExt};
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
 use std::io;
-use std::io::{Read, Write};
 
 pub const HEADER_SIZE: usize = 4;
+/// The destination unreachable code for a datagram a packet filter refused
+pub const CODE_ADMIN_PROHIBITED: u8 = 13;
+/// The time exceeded code for a datagram whose TTL ran out in transit
+pub const CODE_TTL_EXCEEDED: u8 = 0;
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq, FromPrimitive, ToPrimitive)]
@@ -12,6 +17,14 @@ pub enum MsgType {
     EchoReply = 0,
     DestinationUnreachable = 3,
     EchoRequest = 8,
+    TimeExceeded = 11,
+}
+
+/// Whether a message type is an error: destination unreachable, source quench, redirect,
+/// time exceeded or parameter problem. These quote the datagram they're about after
+/// their eight byte header.
+pub fn is_error(msg_type: u8) -> bool {
+    matches!(msg_type, 3 | 4 | 5 | 11 | 12)
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -22,11 +35,19 @@ pub struct Header {
 }
 
 impl Header {
-    pub fn decode(buf: &[u8]) -> io::Result<(Self, &[u8])> {
+    pub fn decode(buf: &[u8]) -> Result<(Self, &[u8])> {
+        check_decode_len(Layer::Icmpv4, buf, HEADER_SIZE)?;
         let mut cursor = io::Cursor::new(buf);
-        let msg_type = match FromPrimitive::from_u8(cursor.read_u8()?) {
+        let raw_msg_type = cursor.read_u8()?;
+        let msg_type = match FromPrimitive::from_u8(raw_msg_type) {
             Some(msg_type) => msg_type,
-            None => return Err(io::ErrorKind::Unsupported.into()),
+            None => {
+                return Err(NettyError::UnknownValue {
+                    layer: Layer::Icmpv4,
+                    field: "message type",
+                    value: raw_msg_type.into(),
+                })
+            }
         };
         let code = cursor.read_u8()?;
         let checksum = cursor.read_u16::<NetworkEndian>()?;
@@ -40,7 +61,8 @@ impl Header {
         ))
     }
 
-    pub fn encode(self, buf: &mut [u8]) -> io::Result<usize> {
+    pub fn encode(self, buf: &mut [u8]) -> Result<usize> {
+        check_encode_len(Layer::Icmpv4, buf, HEADER_SIZE)?;
         let mut cursor = io::Cursor::new(buf);
         cursor.write_u8(self.msg_type.to_u8().unwrap())?;
         cursor.write_u8(self.code)?;
@@ -58,17 +80,130 @@ pub struct EchoHeader {
 pub const ECHO_HEADER_SIZE: usize = 4;
 
 impl EchoHeader {
-    pub fn decode(buf: &[u8]) -> io::Result<(Self, &[u8])> {
+    pub fn decode(buf: &[u8]) -> Result<(Self, &[u8])> {
+        check_decode_len(Layer::Icmpv4, buf, ECHO_HEADER_SIZE)?;
         let mut cursor = io::Cursor::new(buf);
         let id = cursor.read_u16::<NetworkEndian>()?;
         let seq = cursor.read_u16::<NetworkEndian>()?;
         Ok((Self { id, seq }, &buf[ECHO_HEADER_SIZE..]))
     }
 
-    pub fn encode(self, buf: &mut [u8]) -> io::Result<usize> {
+    pub fn encode(self, buf: &mut [u8]) -> Result<usize> {
+        check_encode_len(Layer::Icmpv4, buf, ECHO_HEADER_SIZE)?;
         let mut cursor = io::Cursor::new(buf);
         cursor.write_u16::<NetworkEndian>(self.id)?;
         cursor.write_u16::<NetworkEndian>(self.seq)?;
         Ok(ECHO_HEADER_SIZE)
     }
 }
+
+/// A view of an ICMP message which reads and writes its fields directly in `buffer`.
+/// Every message type has a four byte field after the checksum, which echo messages
+/// use for their id and sequence number.
+#[derive(Clone, Copy, Debug)]
+pub struct Icmpv4Packet<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> Icmpv4Packet<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    pub fn new_checked(buffer: T) -> Result<Self> {
+        check_decode_len(
+            Layer::Icmpv4,
+            buffer.as_ref(),
+            HEADER_SIZE + ECHO_HEADER_SIZE,
+        )?;
+        Ok(Self { buffer })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    pub fn msg_type(&self) -> u8 {
+        self.buffer.as_ref()[0]
+    }
+
+    pub fn code(&self) -> u8 {
+        self.buffer.as_ref()[1]
+    }
+
+    pub fn checksum(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[2..4])
+    }
+
+    pub fn echo_id(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[4..6])
+    }
+
+    pub fn echo_seq(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[6..8])
+    }
+
+    /// Everything after the header, e.g. echo data or the datagram an error is about
+    pub fn data(&self) -> &[u8] {
+        &self.buffer.as_ref()[HEADER_SIZE + ECHO_HEADER_SIZE..]
+    }
+
+    /// Checks the checksum over the whole message
+    pub fn verify_checksum(&self) -> bool {
+        util::checksum(self.buffer.as_ref()) == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Icmpv4Packet<T> {
+    pub fn set_msg_type(&mut self, msg_type: u8) {
+        self.buffer.as_mut()[0] = msg_type;
+    }
+
+    pub fn set_code(&mut self, code: u8) {
+        self.buffer.as_mut()[1] = code;
+    }
+
+    pub fn set_checksum(&mut self, checksum: u16) {
+        NetworkEndian::write_u16(&mut self.buffer.as_mut()[2..4], checksum);
+    }
+
+    pub fn set_echo_id(&mut self, id: u16) {
+        NetworkEndian::write_u16(&mut self.buffer.as_mut()[4..6], id);
+    }
+
+    pub fn set_echo_seq(&mut self, seq: u16) {
+        NetworkEndian::write_u16(&mut self.buffer.as_mut()[6..8], seq);
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer.as_mut()[HEADER_SIZE + ECHO_HEADER_SIZE..]
+    }
+
+    /// Recalculates the checksum over the whole message
+    pub fn fill_checksum(&mut self) {
+        self.set_checksum(0);
+        let checksum = util::checksum(self.buffer.as_ref());
+        self.set_checksum(checksum);
+    }
+}
+
+mod tests {
+    #[test]
+    fn decode_encode_round_trip() {
+        use crate::icmpv4::*;
+        let mut rng = crate::util::Rng::new(1);
+        for _ in 0..1000 {
+            let mut buf = [0u8; HEADER_SIZE + ECHO_HEADER_SIZE];
+            buf.iter_mut().for_each(|byte| *byte = rng.next_u64() as u8);
+            // Mostly use known message types so that the decode usually succeeds
+            buf[0] = [0, 3, 8, rng.next_u64() as u8][rng.next_u64() as usize % 4];
+            if let Ok((header, payload)) = Header::decode(&buf) {
+                let (echo, _) = EchoHeader::decode(payload).unwrap();
+                let mut encoded = [0u8; HEADER_SIZE + ECHO_HEADER_SIZE];
+                header.encode(&mut encoded).unwrap();
+                echo.encode(&mut encoded[HEADER_SIZE..]).unwrap();
+                assert_eq!(encoded, buf);
+            }
+        }
+    }
+}
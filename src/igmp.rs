@@ -0,0 +1,171 @@
+use crate::error::{check_decode_len, check_encode_len, Layer, Result};
+use crate::util;
+use byteorder::{ByteOrder, NetworkEndian};
+use num_derive::{FromPrimitive, ToPrimitive};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+/// The size of an IGMPv1/v2 message, and of the fixed part of an IGMPv3 report
+pub const HEADER_SIZE: usize = 8;
+/// The size of the fixed part of an IGMPv3 query. Shorter queries are from v1/v2 routers.
+pub const V3_QUERY_SIZE: usize = 12;
+/// The size of an IGMPv3 group record without any sources
+pub const GROUP_RECORD_SIZE: usize = 8;
+
+/// Every multicast capable host is a member of this group, and it's never reported
+pub const ALL_SYSTEMS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 1);
+/// IGMPv2 leave messages are sent here
+pub const ALL_ROUTERS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 2);
+/// IGMPv3 reports are sent here
+pub const ALL_V3_ROUTERS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 22);
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, FromPrimitive, ToPrimitive)]
+pub enum MsgType {
+    MembershipQuery = 0x11,
+    V1MembershipReport = 0x12,
+    V2MembershipReport = 0x16,
+    LeaveGroup = 0x17,
+    V3MembershipReport = 0x22,
+}
+
+/// The kind of an IGMPv3 group record. Records without sources are all a host needs to
+/// join (exclude nothing) or leave (include nothing) a group.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, FromPrimitive, ToPrimitive)]
+pub enum RecordType {
+    ModeIsInclude = 1,
+    ModeIsExclude = 2,
+    ChangeToInclude = 3,
+    ChangeToExclude = 4,
+    AllowNewSources = 5,
+    BlockOldSources = 6,
+}
+
+/// A view of an IGMP message which reads and writes its fields directly in `buffer`.
+/// Queries and v1/v2 messages all start with the same eight bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct IgmpPacket<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> IgmpPacket<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    pub fn new_checked(buffer: T) -> Result<Self> {
+        check_decode_len(Layer::Igmp, buffer.as_ref(), HEADER_SIZE)?;
+        Ok(Self { buffer })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    pub fn msg_type(&self) -> u8 {
+        self.buffer.as_ref()[0]
+    }
+
+    pub fn max_resp_code(&self) -> u8 {
+        self.buffer.as_ref()[1]
+    }
+
+    pub fn checksum(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[2..4])
+    }
+
+    pub fn group_addr(&self) -> Ipv4Addr {
+        let octets: [u8; 4] = self.buffer.as_ref()[4..8].try_into().unwrap();
+        Ipv4Addr::from(octets)
+    }
+
+    /// Whether this is a query from an IGMPv3 router, which RFC 3376 tells apart from
+    /// older queries by its length
+    pub fn is_v3_query(&self) -> bool {
+        self.msg_type() == MsgType::MembershipQuery as u8
+            && self.buffer.as_ref().len() >= V3_QUERY_SIZE
+    }
+
+    /// How long a query allows before the report answering it is sent. IGMPv1 queries
+    /// leave this as zero and mean ten seconds.
+    pub fn max_resp_time(&self) -> Duration {
+        let code = self.max_resp_code() as u64;
+        let tenths = if code == 0 {
+            100
+        } else if code < 128 || !self.is_v3_query() {
+            code
+        } else {
+            // A floating point value with a 3 bit exponent and 4 bit mantissa
+            let exp = (code >> 4) & 0x7;
+            let mant = code & 0xf;
+            (mant | 0x10) << (exp + 3)
+        };
+        Duration::from_millis(tenths * 100)
+    }
+
+    /// Checks the checksum over the whole message
+    pub fn verify_checksum(&self) -> bool {
+        util::checksum(self.buffer.as_ref()) == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> IgmpPacket<T> {
+    pub fn set_msg_type(&mut self, msg_type: u8) {
+        self.buffer.as_mut()[0] = msg_type;
+    }
+
+    pub fn set_max_resp_code(&mut self, code: u8) {
+        self.buffer.as_mut()[1] = code;
+    }
+
+    pub fn set_checksum(&mut self, checksum: u16) {
+        NetworkEndian::write_u16(&mut self.buffer.as_mut()[2..4], checksum);
+    }
+
+    pub fn set_group_addr(&mut self, group: Ipv4Addr) {
+        self.buffer.as_mut()[4..8].copy_from_slice(&group.octets());
+    }
+
+    /// Recalculates the checksum over the whole message
+    pub fn fill_checksum(&mut self) {
+        self.set_checksum(0);
+        let checksum = util::checksum(self.buffer.as_ref());
+        self.set_checksum(checksum);
+    }
+}
+
+/// Encodes an IGMPv2 report or leave message for `group`, including its checksum
+pub fn encode_v2_message(msg_type: MsgType, group: Ipv4Addr, buf: &mut [u8]) -> Result<usize> {
+    check_encode_len(Layer::Igmp, buf, HEADER_SIZE)?;
+    let mut packet = IgmpPacket::new_unchecked(&mut buf[..HEADER_SIZE]);
+    packet.set_msg_type(msg_type as u8);
+    packet.set_max_resp_code(0);
+    packet.set_group_addr(group);
+    packet.fill_checksum();
+    Ok(HEADER_SIZE)
+}
+
+/// The size of an IGMPv3 report holding `records` group records without sources
+pub fn v3_report_len(records: usize) -> usize {
+    HEADER_SIZE + records * GROUP_RECORD_SIZE
+}
+
+/// Encodes an IGMPv3 report with a source-less group record for each of `records`,
+/// including its checksum
+pub fn encode_v3_report(records: &[(RecordType, Ipv4Addr)], buf: &mut [u8]) -> Result<usize> {
+    let len = v3_report_len(records.len());
+    check_encode_len(Layer::Igmp, buf, len)?;
+    let buf = &mut buf[..len];
+    buf.fill(0);
+    buf[0] = MsgType::V3MembershipReport as u8;
+    NetworkEndian::write_u16(&mut buf[6..8], records.len() as u16);
+    for (idx, (record_type, group)) in records.iter().enumerate() {
+        let record = &mut buf[HEADER_SIZE + idx * GROUP_RECORD_SIZE..][..GROUP_RECORD_SIZE];
+        record[0] = *record_type as u8;
+        record[4..8].copy_from_slice(&group.octets());
+    }
+    let checksum = util::checksum(buf);
+    NetworkEndian::write_u16(&mut buf[2..4], checksum);
+    Ok(len)
+}
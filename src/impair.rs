@@ -0,0 +1,303 @@
+use crate::util::Rng;
+use crate::{Device, PACKET_SIZE};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// What happens to frames going one way through an `ImpairedDevice`. Probabilities are
+/// between 0 and 1, and the default leaves frames alone.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Impairment {
+    /// Chance a frame is lost
+    pub loss: f64,
+    /// How long every frame is held back
+    pub delay: Duration,
+    /// Up to this much more delay is added at random to each frame
+    pub jitter: Duration,
+    /// Chance a frame skips the delay, overtaking frames which are still held back
+    pub reorder: f64,
+    /// Chance a frame is delivered twice
+    pub duplicate: f64,
+    /// Chance one random bit of a frame is flipped
+    pub corrupt: f64,
+}
+
+/// A device which loses, delays, reorders, duplicates and corrupts the frames going
+/// through another one, to see how the stack and its peers cope with a bad network.
+/// Random choices come from a seeded generator, so a run can be repeated.
+///
+/// Frames the stack writes are held back by the wrapper. Nothing writes them to the inner
+/// device by itself: frames which are due go out whenever the device is read or written,
+/// and `flush` waits until every held frame has gone out. The stack reads all the time it
+/// runs, so that's enough for it, but anything else which only writes has to flush.
+pub struct ImpairedDevice<D: Device + Unpin> {
+    inner: D,
+    rx: Direction,
+    tx: Direction,
+    /// How much of the first held tx frame the inner device has taken so far
+    tx_written: usize,
+    /// The inner device failed to take a held frame. The frame was already accepted, so
+    /// the error is returned from the next write or flush.
+    tx_error: Option<io::Error>,
+    /// The inner device has closed, so only held frames are left to read
+    rx_closed: bool,
+}
+
+impl<D: Device + Unpin> ImpairedDevice<D> {
+    /// Wraps `inner` without impairing anything yet
+    pub fn new(inner: D, seed: u64) -> Self {
+        Self {
+            inner,
+            rx: Direction::new(seed),
+            // Each way has its own generator so its choices don't depend on the other's
+            // traffic
+            tx: Direction::new(!seed),
+            tx_written: 0,
+            tx_error: None,
+            rx_closed: false,
+        }
+    }
+
+    /// Impairs frames read from the inner device
+    pub fn rx(mut self, impairment: Impairment) -> Self {
+        self.rx.impairment = impairment;
+        self
+    }
+
+    /// Impairs frames written to the inner device
+    pub fn tx(mut self, impairment: Impairment) -> Self {
+        self.tx.impairment = impairment;
+        self
+    }
+
+    /// Writes the held frames which are due to the inner device. A frame the device only
+    /// takes part of is finished before the next one is started. A frame the device fails
+    /// to take is dropped, and the error is kept for the next write or flush.
+    fn send_due(&mut self, cx: &mut Context<'_>) {
+        while self.tx.poll_due(cx).is_ready() {
+            let frame = &self.tx.held[0].1[self.tx_written..];
+            let result = match Pin::new(&mut self.inner).poll_write(cx, frame) {
+                Poll::Ready(Ok(0)) if !frame.is_empty() => Err(io::ErrorKind::WriteZero.into()),
+                Poll::Ready(Ok(n)) => Ok(n),
+                Poll::Ready(Err(err)) => Err(err),
+                Poll::Pending => break,
+            };
+            match result {
+                Ok(n) if n < frame.len() => self.tx_written += n,
+                Ok(_) => {
+                    self.tx.held.pop_front();
+                    self.tx_written = 0;
+                }
+                Err(err) => {
+                    self.tx.held.pop_front();
+                    self.tx_written = 0;
+                    self.tx_error = Some(err);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The frames held back going one way
+struct Direction {
+    impairment: Impairment,
+    rng: Rng,
+    /// Frames with when they're due, in the order they're due
+    held: VecDeque<(Instant, Vec<u8>)>,
+    /// Wakes the task when the first held frame is due. It's made on first use, as it
+    /// needs a runtime.
+    timer: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl Direction {
+    fn new(seed: u64) -> Self {
+        Self {
+            impairment: Impairment::default(),
+            rng: Rng::new(seed),
+            held: VecDeque::new(),
+            timer: None,
+        }
+    }
+
+    /// Holds the copies of `frame` which survive until they're due
+    fn push(&mut self, frame: &[u8]) {
+        let impairment = self.impairment;
+        if self.rng.chance(impairment.loss) {
+            log::debug!("Impairment lost a frame");
+            return;
+        }
+        let copies = match self.rng.chance(impairment.duplicate) {
+            true => 2,
+            false => 1,
+        };
+        let now = Instant::now();
+        for _ in 0..copies {
+            let mut copy = frame.to_vec();
+            if !copy.is_empty() && self.rng.chance(impairment.corrupt) {
+                let bit = (self.rng.next_u64() % (copy.len() as u64 * 8)) as usize;
+                copy[bit / 8] ^= 1 << (bit % 8);
+            }
+            let due_at = match self.rng.chance(impairment.reorder) {
+                true => now,
+                false => now + impairment.delay + impairment.jitter.mul_f64(self.rng.unit()),
+            };
+            let idx = self
+                .held
+                .partition_point(|(held_due_at, _)| *held_due_at <= due_at);
+            self.held.insert(idx, (due_at, copy));
+        }
+    }
+
+    /// Ready once the first held frame is due. Until then the timer wakes the task when
+    /// it will be, if any frame is held.
+    fn poll_due(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let due_at = match self.held.front() {
+            Some((due_at, _)) => *due_at,
+            None => return Poll::Pending,
+        };
+        if due_at <= Instant::now() {
+            return Poll::Ready(());
+        }
+        let timer = self
+            .timer
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(due_at.into())));
+        timer.as_mut().reset(due_at.into());
+        timer.as_mut().poll(cx)
+    }
+}
+
+impl<D: Device + Unpin> AsyncRead for ImpairedDevice<D> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.send_due(cx);
+        loop {
+            if this.rx.poll_due(cx).is_ready() {
+                let (_, frame) = this.rx.held.pop_front().unwrap();
+                let len = std::cmp::min(frame.len(), buf.remaining());
+                buf.put_slice(&frame[..len]);
+                return Poll::Ready(Ok(()));
+            }
+            if this.rx_closed {
+                return match this.rx.held.is_empty() {
+                    true => Poll::Ready(Ok(())),
+                    false => Poll::Pending,
+                };
+            }
+            let mut scratch = [0u8; PACKET_SIZE];
+            let mut frame = ReadBuf::new(&mut scratch);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut frame) {
+                Poll::Ready(Ok(())) if frame.filled().is_empty() => this.rx_closed = true,
+                Poll::Ready(Ok(())) => this.rx.push(frame.filled()),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<D: Device + Unpin> AsyncWrite for ImpairedDevice<D> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if let Some(err) = this.tx_error.take() {
+            return Poll::Ready(Err(err));
+        }
+        this.tx.push(buf);
+        this.send_due(cx);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.send_due(cx);
+        if let Some(err) = this.tx_error.take() {
+            return Poll::Ready(Err(err));
+        }
+        // The timer or the inner device wakes the task when more can be written
+        if !this.tx.held.is_empty() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+mod tests {
+    #[tokio::test]
+    async fn impairs_frames_reproducibly() {
+        use crate::impair::*;
+        use crate::MemoryDevice;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // The same seed loses the same frames
+        let lost = |seed| async move {
+            let (device, mut handle) = MemoryDevice::new();
+            let mut device = ImpairedDevice::new(device, seed).tx(Impairment {
+                loss: 0.5,
+                ..Impairment::default()
+            });
+            for idx in 0..32u8 {
+                device.write_all(&[idx]).await.unwrap();
+            }
+            let mut sent = Vec::new();
+            while let Some(frame) = handle.try_recv() {
+                sent.push(frame[0]);
+            }
+            sent
+        };
+        let sent = lost(7).await;
+        assert!(!sent.is_empty() && sent.len() < 32);
+        assert_eq!(sent, lost(7).await);
+        assert_ne!(sent, lost(8).await);
+
+        // Received frames are held back, doubled and corrupted
+        let (device, handle) = MemoryDevice::new();
+        let delay = Duration::from_millis(20);
+        let mut device = ImpairedDevice::new(device, 1).rx(Impairment {
+            delay,
+            duplicate: 1.0,
+            corrupt: 1.0,
+            ..Impairment::default()
+        });
+        let frame = [0u8; 8];
+        handle.inject(&frame);
+        let start = Instant::now();
+        let mut buf = [0u8; PACKET_SIZE];
+        for _ in 0..2 {
+            let n = device.read(&mut buf).await.unwrap();
+            assert_eq!(n, frame.len());
+            let flipped: u32 = buf[..n].iter().map(|byte| byte.count_ones()).sum();
+            assert_eq!(flipped, 1);
+        }
+        assert!(start.elapsed() >= delay);
+        assert!(device.tx.held.is_empty());
+
+        // Written frames are held back until they're due, and flushing waits for them
+        let (device, mut handle) = MemoryDevice::new();
+        let mut device = ImpairedDevice::new(device, 1).tx(Impairment {
+            delay,
+            ..Impairment::default()
+        });
+        let start = Instant::now();
+        device.write_all(&frame).await.unwrap();
+        assert!(handle.try_recv().is_none());
+        device.flush().await.unwrap();
+        assert!(start.elapsed() >= delay);
+        assert_eq!(handle.try_recv().unwrap(), frame);
+    }
+}
@@ -1,4 +1,6 @@
-use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use crate::error::{check_decode_len, check_encode_len, Layer, NettyError, Result};
+use crate::util;
+use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt, WriteBytesExt};
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
 use std::io;
@@ -6,11 +8,16 @@ use std::io::{Read, Write};
 use std::net::Ipv4Addr;
 
 pub const HEADER_SIZE: usize = 20;
+pub const VERSION: u8 = 4;
+/// The control flag telling routers not to fragment the datagram
+pub const FLAG_DONT_FRAGMENT: u8 = 0b010;
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq, FromPrimitive, ToPrimitive)]
 pub enum ProtocolType {
     IcmpV4 = 1,
+    Igmp = 2,
+    Udp = 17,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -30,21 +37,29 @@ pub struct Header {
 }
 
 impl Header {
-    pub fn decode(buf: &[u8]) -> io::Result<(Self, &[u8])> {
+    pub fn decode(buf: &[u8]) -> Result<(Self, &[u8])> {
+        check_decode_len(Layer::Ipv4, buf, HEADER_SIZE)?;
         let mut cursor = io::Cursor::new(buf);
         let version_and_ihl = cursor.read_u8()?;
-        let version = version_and_ihl & 0xF;
-        let internet_header_len = version_and_ihl >> 4;
+        let version = version_and_ihl >> 4;
+        let internet_header_len = version_and_ihl & 0xF;
         let type_of_service = cursor.read_u8()?;
         let datagram_len = cursor.read_u16::<NetworkEndian>()?;
         let id = cursor.read_u16::<NetworkEndian>()?;
         let flags_and_frag_offset = cursor.read_u16::<NetworkEndian>()?;
-        let control_flags = (flags_and_frag_offset & 0b111) as u8;
-        let fragment_offset = flags_and_frag_offset >> 3;
+        let control_flags = (flags_and_frag_offset >> 13) as u8;
+        let fragment_offset = flags_and_frag_offset & 0x1FFF;
         let time_to_live = cursor.read_u8()?;
-        let proto = match FromPrimitive::from_u8(cursor.read_u8()?) {
+        let raw_proto = cursor.read_u8()?;
+        let proto = match FromPrimitive::from_u8(raw_proto) {
             Some(proto) => proto,
-            None => return Err(io::ErrorKind::Unsupported.into()),
+            None => {
+                return Err(NettyError::UnknownValue {
+                    layer: Layer::Ipv4,
+                    field: "protocol",
+                    value: raw_proto.into(),
+                })
+            }
         };
         let checksum = cursor.read_u16::<NetworkEndian>()?;
         let mut src_addr_octets = [0u8; 4];
@@ -73,14 +88,15 @@ impl Header {
         ))
     }
 
-    pub fn encode(self, buf: &mut [u8]) -> io::Result<usize> {
+    pub fn encode(self, buf: &mut [u8]) -> Result<usize> {
+        check_encode_len(Layer::Ipv4, buf, HEADER_SIZE)?;
         let mut cursor = io::Cursor::new(buf);
-        cursor.write_u8((self.version & 0xF) | (self.internet_header_len << 4))?;
+        cursor.write_u8((self.version << 4) | (self.internet_header_len & 0xF))?;
         cursor.write_u8(self.type_of_service)?;
         cursor.write_u16::<NetworkEndian>(self.datagram_len)?;
         cursor.write_u16::<NetworkEndian>(self.id)?;
         cursor.write_u16::<NetworkEndian>(
-            (self.control_flags as u16 & 0b111) | (self.fragment_offset << 3),
+            ((self.control_flags as u16 & 0b111) << 13) | (self.fragment_offset & 0x1FFF),
         )?;
         cursor.write_u8(self.time_to_live)?;
         cursor.write_u8(self.proto.to_u8().unwrap())?;
@@ -90,3 +106,243 @@ impl Header {
         Ok(HEADER_SIZE)
     }
 }
+
+/// A block of addresses given by a network address and prefix length, e.g. 10.0.0.0/24
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Ipv4Cidr {
+    network: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    /// Any host bits set in `addr` are cleared. Panics if `prefix_len` is more than 32.
+    pub fn new(addr: Ipv4Addr, prefix_len: u8) -> Self {
+        assert!(prefix_len <= 32, "prefix length {} is too long", prefix_len);
+        let network = Ipv4Addr::from(u32::from(addr) & Self::mask_bits(prefix_len));
+        Self {
+            network,
+            prefix_len,
+        }
+    }
+
+    fn mask_bits(prefix_len: u8) -> u32 {
+        u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+    }
+
+    pub fn network(&self) -> Ipv4Addr {
+        self.network
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(Self::mask_bits(self.prefix_len))
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & Self::mask_bits(self.prefix_len) == u32::from(self.network)
+    }
+}
+
+impl std::fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// A view of an IPv4 datagram which reads and writes the header fields directly in
+/// `buffer`. `new_checked` makes sure the header and total lengths fit in the buffer, and
+/// that the total length covers the header.
+#[derive(Clone, Copy, Debug)]
+pub struct Ipv4Packet<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> Ipv4Packet<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    pub fn new_checked(buffer: T) -> Result<Self> {
+        check_decode_len(Layer::Ipv4, buffer.as_ref(), HEADER_SIZE)?;
+        let packet = Self { buffer };
+        if packet.version() != VERSION {
+            return Err(NettyError::UnknownValue {
+                layer: Layer::Ipv4,
+                field: "version",
+                value: packet.version().into(),
+            });
+        }
+        if packet.header_len() < HEADER_SIZE {
+            return Err(NettyError::UnknownValue {
+                layer: Layer::Ipv4,
+                field: "header length",
+                value: packet.header_len() as u32,
+            });
+        }
+        if (packet.total_len() as usize) < packet.header_len() {
+            return Err(NettyError::UnknownValue {
+                layer: Layer::Ipv4,
+                field: "total length",
+                value: packet.total_len().into(),
+            });
+        }
+        check_decode_len(
+            Layer::Ipv4,
+            packet.buffer.as_ref(),
+            packet.total_len() as usize,
+        )?;
+        Ok(packet)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    pub fn version(&self) -> u8 {
+        self.buffer.as_ref()[0] >> 4
+    }
+
+    /// The header length in bytes, including any options
+    pub fn header_len(&self) -> usize {
+        (self.buffer.as_ref()[0] & 0xF) as usize * 4
+    }
+
+    pub fn type_of_service(&self) -> u8 {
+        self.buffer.as_ref()[1]
+    }
+
+    pub fn total_len(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[2..4])
+    }
+
+    pub fn id(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[4..6])
+    }
+
+    pub fn control_flags(&self) -> u8 {
+        self.buffer.as_ref()[6] >> 5
+    }
+
+    pub fn fragment_offset(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[6..8]) & 0x1FFF
+    }
+
+    pub fn time_to_live(&self) -> u8 {
+        self.buffer.as_ref()[8]
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.buffer.as_ref()[9]
+    }
+
+    pub fn checksum(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[10..12])
+    }
+
+    pub fn src_addr(&self) -> Ipv4Addr {
+        let octets: [u8; 4] = self.buffer.as_ref()[12..16].try_into().unwrap();
+        Ipv4Addr::from(octets)
+    }
+
+    pub fn dst_addr(&self) -> Ipv4Addr {
+        let octets: [u8; 4] = self.buffer.as_ref()[16..20].try_into().unwrap();
+        Ipv4Addr::from(octets)
+    }
+
+    /// The datagram's payload, without any link layer padding which follows it
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[self.header_len()..self.total_len() as usize]
+    }
+
+    /// Checks the checksum over the header
+    pub fn verify_checksum(&self) -> bool {
+        util::checksum(&self.buffer.as_ref()[..self.header_len()]) == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Ipv4Packet<T> {
+    pub fn set_type_of_service(&mut self, type_of_service: u8) {
+        self.buffer.as_mut()[1] = type_of_service;
+    }
+
+    pub fn set_total_len(&mut self, total_len: u16) {
+        NetworkEndian::write_u16(&mut self.buffer.as_mut()[2..4], total_len);
+    }
+
+    pub fn set_id(&mut self, id: u16) {
+        NetworkEndian::write_u16(&mut self.buffer.as_mut()[4..6], id);
+    }
+
+    pub fn set_time_to_live(&mut self, time_to_live: u8) {
+        self.buffer.as_mut()[8] = time_to_live;
+    }
+
+    pub fn set_protocol(&mut self, protocol: u8) {
+        self.buffer.as_mut()[9] = protocol;
+    }
+
+    pub fn set_checksum(&mut self, checksum: u16) {
+        NetworkEndian::write_u16(&mut self.buffer.as_mut()[10..12], checksum);
+    }
+
+    pub fn set_src_addr(&mut self, addr: Ipv4Addr) {
+        self.buffer.as_mut()[12..16].copy_from_slice(&addr.octets());
+    }
+
+    pub fn set_dst_addr(&mut self, addr: Ipv4Addr) {
+        self.buffer.as_mut()[16..20].copy_from_slice(&addr.octets());
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let (start, end) = (self.header_len(), self.total_len() as usize);
+        &mut self.buffer.as_mut()[start..end]
+    }
+
+    /// Recalculates the checksum over the header
+    pub fn fill_checksum(&mut self) {
+        self.set_checksum(0);
+        let header_len = self.header_len();
+        let checksum = util::checksum(&self.buffer.as_ref()[..header_len]);
+        self.set_checksum(checksum);
+    }
+}
+
+mod tests {
+    #[test]
+    fn rejects_total_length_inside_header() {
+        use crate::ipv4::*;
+        let mut buf = [0u8; HEADER_SIZE + 4];
+        buf[0] = 0x45;
+        let mut ip_packet = Ipv4Packet::new_unchecked(&mut buf[..]);
+        ip_packet.set_total_len(HEADER_SIZE as u16 - 1);
+        assert!(matches!(
+            Ipv4Packet::new_checked(&buf[..]),
+            Err(NettyError::UnknownValue { .. })
+        ));
+        Ipv4Packet::new_unchecked(&mut buf[..]).set_total_len(HEADER_SIZE as u16 + 4);
+        assert_eq!(
+            Ipv4Packet::new_checked(&buf[..]).unwrap().payload().len(),
+            4
+        );
+    }
+
+    #[test]
+    fn decode_encode_round_trip() {
+        use crate::ipv4::*;
+        let mut rng = crate::util::Rng::new(1);
+        for _ in 0..1000 {
+            let mut buf = [0u8; HEADER_SIZE];
+            buf.iter_mut().for_each(|byte| *byte = rng.next_u64() as u8);
+            // Mostly use known protocols so that the decode usually succeeds
+            buf[9] = [1, 17, rng.next_u64() as u8][rng.next_u64() as usize % 3];
+            if let Ok((header, _)) = Header::decode(&buf) {
+                let mut encoded = [0u8; HEADER_SIZE];
+                header.encode(&mut encoded).unwrap();
+                assert_eq!(encoded, buf);
+            }
+        }
+    }
+}
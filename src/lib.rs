@@ -1,27 +1,123 @@
+use num_traits::FromPrimitive;
+use std::collections::HashMap;
 use std::io;
 use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio_tun::Tun;
 
-mod arp;
-mod eth;
-mod icmpv4;
-mod ipv4;
+pub mod arp;
+pub mod arp_table;
+pub use arp_table::*;
+pub mod builder;
+pub use builder::*;
+pub mod conntrack;
+pub use conntrack::ConnTrack;
+pub mod device;
+pub use device::*;
+pub mod error;
+pub use error::NettyError;
+pub mod eth;
+pub mod filter;
+pub use filter::PacketFilter;
+pub mod icmpv4;
+pub mod igmp;
+pub mod impair;
+pub use impair::{ImpairedDevice, Impairment};
+pub mod ipv4;
+pub mod multicast;
+pub use multicast::*;
+pub mod nat;
+pub use nat::NatTable;
 pub mod packet_pool;
 pub use packet_pool::*;
-mod util;
+pub mod shaper;
+pub use shaper::ShaperConfig;
+pub mod stats;
+pub use stats::*;
+pub mod udp;
+pub mod util;
 
-const ARP_TABLE_ENTRIES: usize = 32;
+/// How long a packet waits for its destination to be resolved before it's dropped
+const ARP_RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+/// How often the stack checks on timers like `ARP_RESOLVE_TIMEOUT`
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_millis(500);
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+/// How often a RARP request is repeated while waiting for a reply
+const RARP_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// The most source MAC addresses the ARP rate limit keeps track of. While that many are
+/// over their limit, ARP packets from new sources are dropped too.
+const ARP_RATE_LIMIT_SOURCES: usize = 1024;
+/// How long the stack answers with IGMPv2 messages after hearing an IGMPv2 query
+const OLDER_QUERIER_TIMEOUT: Duration = Duration::from_secs(400);
+/// How many times a join or leave report is sent, IGMP's default Robustness Variable
+const IGMP_ROBUSTNESS: u32 = 2;
+/// The longest wait before a join or leave report is repeated (IGMPv3's Unsolicited
+/// Report Interval)
+const UNSOLICITED_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// The same for IGMPv2, which waits longer
+const V2_UNSOLICITED_REPORT_INTERVAL: Duration = Duration::from_secs(10);
 
-pub struct NettyStack<'pool, const PKT_POOL_SZ: usize> {
-    reader: ReadHalf<Tun>,
-    writer: WriteHalf<Tun>,
+pub struct NettyStack<'pool, const PKT_POOL_SZ: usize, D: Device = Tun> {
+    reader: ReadHalf<D>,
+    writer: WriteHalf<D>,
     pkt_pool: &'pool PacketPool<'pool, PKT_POOL_SZ>,
-    arp_translation_table: [Option<arp::CacheEntry>; ARP_TABLE_ENTRIES],
-    netdev: NettyDevice<'static>,
+    arp_table: Arc<ArpTable>,
+    netdev: NettyDevice,
+    stats: Arc<Stats>,
+    filter: Arc<PacketFilter>,
+    conntrack: Arc<ConnTrack>,
+    nat: Arc<NatTable>,
+    arp_waiters: Vec<ArpWaiter<'pool, PKT_POOL_SZ>>,
+    multicast: Arc<MulticastGroups<'pool, PKT_POOL_SZ>>,
+    igmp: IgmpState,
+    /// The addresses given to RARP clients, by their MAC address
+    rarp_table: HashMap<[u8; 6], Ipv4Addr>,
+    /// Networks reachable through the stack, whose addresses it answers ARP requests for
+    proxy_arp_prefixes: Vec<ipv4::Ipv4Cidr>,
+    arp_policy: ArpPolicy,
+    /// How many more ARP packets each source MAC address may send for now, for up to
+    /// `ARP_RATE_LIMIT_SOURCES` addresses
+    arp_rate_limits: HashMap<[u8; 6], util::TokenBucket>,
+    /// Queues frames on their way to the device while egress shaping is enabled
+    shaper: Option<shaper::Shaper>,
 }
 
-impl<'pool, const PKT_POOL_SZ: usize> NettyStack<'pool, PKT_POOL_SZ> {
+/// A packet sent by the application whose next hop doesn't have an ARP entry yet
+struct ArpWaiter<'pool, const PKT_POOL_SZ: usize> {
+    packet: Packet<'pool, 'pool, PKT_POOL_SZ>,
+    next_hop: Ipv4Addr,
+    queued_at: Instant,
+}
+
+/// What the stack has told multicast routers about the groups it's a member of
+#[derive(Default)]
+struct IgmpState {
+    /// Groups the stack has sent a join report for
+    reported: Vec<Ipv4Addr>,
+    /// Reports answering a query, and when they're due
+    pending_reports: Vec<(Ipv4Addr, Instant)>,
+    /// Join and leave reports which are still to be repeated
+    state_changes: Vec<StateChange>,
+    /// An IGMPv2 router is present until this time, so reports must be IGMPv2 messages
+    v2_querier_until: Option<Instant>,
+    /// Picks the random delays before reports
+    rng: util::Rng,
+}
+
+/// A join or leave report which is sent more than once, so a single lost report doesn't
+/// leave routers with the wrong idea
+struct StateChange {
+    group: Ipv4Addr,
+    record_type: igmp::RecordType,
+    /// When the next repeat is due
+    due: Instant,
+    remaining: u32,
+}
+
+impl<'pool, const PKT_POOL_SZ: usize> NettyStack<'pool, PKT_POOL_SZ, Tun> {
+    /// Creates a TAP device called `if_name` and runs the stack on it
     pub fn new<'a>(
         if_name: &'a str,
         pkt_pool: &'pool PacketPool<'pool, PKT_POOL_SZ>,
@@ -32,199 +128,1974 @@ impl<'pool, const PKT_POOL_SZ: usize> NettyStack<'pool, PKT_POOL_SZ> {
             .packet_info(false)
             .up()
             .try_build()?;
-        let (reader, writer) = tokio::io::split(tun);
-        Ok(Self {
+        let mut stack = Self::with_device(tun, pkt_pool);
+        stack.netdev.name = if_name.to_string();
+        Ok(stack)
+    }
+}
+
+impl<'pool, const PKT_POOL_SZ: usize, D: Device> NettyStack<'pool, PKT_POOL_SZ, D> {
+    /// Runs the stack on an existing device, such as a `MemoryDevice`
+    pub fn with_device(device: D, pkt_pool: &'pool PacketPool<'pool, PKT_POOL_SZ>) -> Self {
+        let (reader, writer) = tokio::io::split(device);
+        Self {
             reader,
             writer,
             pkt_pool,
-            arp_translation_table: [None; ARP_TABLE_ENTRIES],
+            arp_table: Arc::new(ArpTable::new()),
             netdev: NettyDevice {
                 hwaddr: [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff],
                 ipaddr: Ipv4Addr::new(10, 0, 0, 2),
-                name: "mock_dev",
+                netmask: Ipv4Addr::new(255, 255, 255, 0),
+                name: "mock_dev".to_string(),
+                promiscuous: false,
+                rx_checksum_offload: false,
             },
-        })
+            stats: Arc::new(Stats::new()),
+            filter: Arc::new(PacketFilter::new()),
+            conntrack: Arc::new(ConnTrack::new()),
+            nat: Arc::new(NatTable::new()),
+            arp_waiters: Vec::new(),
+            multicast: Arc::new(MulticastGroups::new()),
+            igmp: IgmpState::default(),
+            rarp_table: HashMap::new(),
+            proxy_arp_prefixes: Vec::new(),
+            arp_policy: ArpPolicy::default(),
+            arp_rate_limits: HashMap::new(),
+            shaper: None,
+        }
     }
 
-    pub async fn run(&mut self) -> io::Result<()> {
-        let mut buf = [0u8; 2048];
-        loop {
-            let n = self.reader.read(&mut buf).await?;
-            log::info!("Got {} bytes", n);
-            if let Ok((header, eth_payload)) = eth::Header::decode(&buf[..n]) {
-                match header.ethertype {
-                    eth::Ethertype::ARP => {
-                        if let Err(err) = self.handle_arp(eth_payload).await {
-                            log::error!("Error handling arp: {}", err);
+    /// Gives a handle to the stack's counters which can be read while the stack runs.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Gives a handle to the neighbor cache which can be used while the stack runs.
+    pub fn arp_table(&self) -> Arc<ArpTable> {
+        self.arp_table.clone()
+    }
+
+    /// Gives a handle to the packet filter's rule chains which can be edited while the
+    /// stack runs.
+    pub fn filter(&self) -> Arc<PacketFilter> {
+        self.filter.clone()
+    }
+
+    /// Gives a handle to the flows seen by the connection tracker while the stack runs.
+    pub fn conntrack(&self) -> Arc<ConnTrack> {
+        self.conntrack.clone()
+    }
+
+    /// Gives a handle to the NAT configuration and mappings which can be used while the
+    /// stack runs.
+    pub fn nat(&self) -> Arc<NatTable> {
+        self.nat.clone()
+    }
+
+    /// Gives a handle for joining and leaving multicast groups while the stack runs.
+    pub fn multicast(&self) -> Arc<MulticastGroups<'pool, PKT_POOL_SZ>> {
+        self.multicast.clone()
+    }
+
+    /// Sets the address of this device. An unspecified address can be learnt with
+    /// `learn_ipaddr`.
+    pub fn set_ipaddr(&mut self, ipaddr: Ipv4Addr, netmask: Ipv4Addr) {
+        self.netdev.ipaddr = ipaddr;
+        self.netdev.netmask = netmask;
+    }
+
+    pub fn ipaddr(&self) -> Ipv4Addr {
+        self.netdev.ipaddr
+    }
+
+    /// Answers RARP requests from `mac` with `ipaddr`. The stack acts as a RARP server
+    /// while it has any entries.
+    pub fn add_rarp_entry(&mut self, mac: [u8; 6], ipaddr: Ipv4Addr) {
+        self.rarp_table.insert(mac, ipaddr);
+    }
+
+    pub fn remove_rarp_entry(&mut self, mac: [u8; 6]) -> Option<Ipv4Addr> {
+        self.rarp_table.remove(&mac)
+    }
+
+    /// Answers ARP requests for addresses in `prefix` with this device's MAC address, so
+    /// hosts on this segment send traffic for them through the stack.
+    pub fn add_proxy_arp(&mut self, prefix: ipv4::Ipv4Cidr) {
+        if !self.proxy_arp_prefixes.contains(&prefix) {
+            self.proxy_arp_prefixes.push(prefix);
+        }
+    }
+
+    pub fn remove_proxy_arp(&mut self, prefix: ipv4::Ipv4Cidr) {
+        self.proxy_arp_prefixes.retain(|&p| p != prefix);
+    }
+
+    /// Sets how much the stack trusts the ARP packets it receives
+    pub fn set_arp_policy(&mut self, policy: ArpPolicy) {
+        self.arp_policy = policy;
+        self.arp_rate_limits.clear();
+    }
+
+    /// Limits the rate frames are written to the device, queueing them by priority
+    /// until they can be sent. `None` writes frames as soon as they're ready. Frames
+    /// still queued when the limits change are dropped.
+    pub fn set_egress_shaping(&mut self, config: Option<ShaperConfig>) {
+        self.shaper = config.map(shaper::Shaper::new);
+    }
+
+    /// How many frames are waiting in each egress priority queue
+    pub fn egress_queued(&self) -> [usize; shaper::PRIORITY_QUEUES] {
+        self.shaper
+            .as_ref()
+            .map_or([0; shaper::PRIORITY_QUEUES], shaper::Shaper::queued)
+    }
+
+    /// Asks RARP servers for this device's address and uses the first one given,
+    /// repeating the request until `timeout` runs out. Other frames received meanwhile
+    /// are handled as usual. RARP only gives an address, so the netmask is left as it
+    /// is; set it with `set_ipaddr` if the network needs another. If no address is
+    /// learnt the device keeps the one it had.
+    pub async fn learn_ipaddr(&mut self, timeout: Duration) -> error::Result<Ipv4Addr> {
+        let old_ipaddr = self.netdev.ipaddr;
+        self.netdev.ipaddr = Ipv4Addr::UNSPECIFIED;
+        let learnt = self.wait_for_rarp_reply(timeout).await;
+        if learnt.is_err() {
+            self.netdev.ipaddr = old_ipaddr;
+        }
+        learnt
+    }
+
+    /// Runs the stack without an address until a RARP reply gives it one
+    async fn wait_for_rarp_reply(&mut self, timeout: Duration) -> error::Result<Ipv4Addr> {
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+        let mut retry = tokio::time::interval(RARP_RETRY_INTERVAL);
+        let mut rx_packet = None;
+        while self.netdev.ipaddr.is_unspecified() {
+            if rx_packet.is_none() {
+                rx_packet = self.pkt_pool.allocate();
+            }
+            let egress_deadline = self.egress_deadline();
+            tokio::select! {
+                n = Self::read_frame(&mut self.reader, rx_packet.as_mut()) => {
+                    let n = n?;
+                    match rx_packet.take() {
+                        Some(packet) if n == 0 => {
+                            packet.discard();
+                            return Err(NettyError::NoRarpReply);
                         }
-                    }
-                    eth::Ethertype::IPv4 => {
-                        if let Err(err) = self.handle_ipv4(eth_payload).await {
-                            log::error!("Error handling ip: {}", err);
+                        Some(mut packet) => {
+                            packet.put(n)?;
+                            self.handle_frame(&mut packet).await;
+                            packet.discard();
                         }
+                        None if n == 0 => return Err(NettyError::NoRarpReply),
+                        None => self.stats.record_drop(DropReason::PoolExhausted),
                     }
-                    _ => {
-                        log::info!("Unhandled ethertype: {:?}", header.ethertype);
+                }
+                _ = tokio::time::sleep_until(egress_deadline.unwrap_or_else(Instant::now).into()),
+                    if egress_deadline.is_some() =>
+                {
+                    if let Err(err) = self.send_queued().await {
+                        log::error!("Error sending queued frame: {}", err);
                     }
                 }
+                _ = retry.tick() => {
+                    if let Err(err) = self.send_rarp_request().await {
+                        log::error!("Error sending RARP request: {}", err);
+                        self.record_error(&err);
+                    }
+                }
+                _ = &mut deadline => {
+                    if let Some(packet) = rx_packet.take() {
+                        packet.discard();
+                    }
+                    return Err(NettyError::NoRarpReply);
+                }
             }
         }
+        if let Some(packet) = rx_packet.take() {
+            packet.discard();
+        }
+        Ok(self.netdev.ipaddr)
+    }
+
+    /// Handles every frame the device receives, not only those sent to this device, a
+    /// broadcast or a joined multicast group.
+    pub fn set_promiscuous(&mut self, promiscuous: bool) {
+        self.netdev.promiscuous = promiscuous;
+    }
+
+    /// Trusts that the device has already verified the checksums of received packets, so
+    /// the stack doesn't check them again.
+    pub fn set_rx_checksum_offload(&mut self, offload: bool) {
+        self.netdev.rx_checksum_offload = offload;
     }
 
-    async fn handle_arp(&mut self, packet: &[u8]) -> io::Result<()> {
-        let (hdr, arp_payload) = arp::Header::decode(packet)?;
-        if hdr.hwtype == arp::HwType::Ethernet {
-            if hdr.protype == arp::ProtocolType::Ipv4 {
-                let (arp_data, _remainder) = arp::Ipv4Data::decode(arp_payload)?;
-                if let Some(Some(mut entry)) =
-                    self.arp_translation_table
-                        .into_iter()
-                        .find(|&entry| match entry {
-                            Some(entry) => entry.hwtype == hdr.hwtype && entry.ip == arp_data.sip,
-                            None => false,
-                        })
+    /// Handles frames from the device and packets sent by the application until the
+    /// device closes.
+    pub async fn run(&mut self) -> io::Result<()> {
+        let mut housekeeping = tokio::time::interval(HOUSEKEEPING_INTERVAL);
+        let mut rx_packet = None;
+        let multicast = self.multicast.clone();
+        // Groups may have been joined before the stack started
+        self.update_memberships().await;
+        loop {
+            if rx_packet.is_none() {
+                rx_packet = self.pkt_pool.allocate();
+            }
+            let egress_deadline = self.egress_deadline();
+            tokio::select! {
+                n = Self::read_frame(&mut self.reader, rx_packet.as_mut()) => {
+                    let n = n?;
+                    if n == 0 {
+                        log::info!("Device closed");
+                        if let Some(packet) = rx_packet.take() {
+                            packet.discard();
+                        }
+                        return Ok(());
+                    }
+                    match rx_packet.take() {
+                        Some(mut packet) => {
+                            packet.put(n)?;
+                            log::info!("Got {} bytes", n);
+                            self.handle_frame(&mut packet).await;
+                            packet.discard();
+                        }
+                        None => {
+                            log::error!("Packet pool exhausted, dropping frame");
+                            self.stats.record_drop(DropReason::PoolExhausted);
+                        }
+                    }
+                }
+                packet = self.pkt_pool.next_to_transmit() => {
+                    if let Err(err) = self.transmit(packet).await {
+                        log::error!("Error transmitting packet: {}", err);
+                        self.record_error(&err);
+                    }
+                }
+                _ = tokio::time::sleep_until(egress_deadline.unwrap_or_else(Instant::now).into()),
+                    if egress_deadline.is_some() =>
                 {
-                    // Update the ARP entry
-                    entry.mac = arp_data.smac;
-                } else {
-                    // Insert a new entry
-                    if let Some(position) = self
-                        .arp_translation_table
-                        .into_iter()
-                        .position(|entry| entry.is_none())
-                    {
-                        self.arp_translation_table[position] = Some(arp::CacheEntry {
-                            hwtype: hdr.hwtype,
-                            ip: arp_data.sip,
-                            mac: arp_data.smac,
-                        });
-                    } else {
-                        log::error!("ARP table is full!");
+                    if let Err(err) = self.send_queued().await {
+                        log::error!("Error sending queued frame: {}", err);
                     }
                 }
+                _ = multicast.changed() => {
+                    self.update_memberships().await;
+                }
+                _ = housekeeping.tick() => {
+                    self.expire_arp_waiters();
+                    self.arp_table.expire();
+                    self.conntrack.expire();
+                    self.nat.expire();
+                    self.arp_rate_limits.retain(|_, bucket| !bucket.is_full());
+                    self.send_due_igmp_reports().await;
+                    self.pkt_pool.check_leaks();
+                }
+            }
+        }
+    }
+
+    /// Reads the next frame from the device into `packet`. Without a packet the frame
+    /// still has to be taken off the device, but it's thrown away.
+    async fn read_frame(
+        reader: &mut ReadHalf<D>,
+        packet: Option<&mut Packet<'pool, 'pool, PKT_POOL_SZ>>,
+    ) -> io::Result<usize> {
+        match packet {
+            Some(packet) => reader.read(packet.tailroom_mut()).await,
+            None => {
+                let mut scratch = [0u8; PACKET_SIZE];
+                reader.read(&mut scratch).await
+            }
+        }
+    }
 
-                // Is it an ARP request?
-                if hdr.opcode == arp::Opcode::ArpRequest {
-                    let reply_hdr = arp::Header {
-                        hwtype: arp::HwType::Ethernet,
-                        protype: arp::ProtocolType::Ipv4,
-                        hwsize: 6,
-                        prosize: 4,
-                        opcode: arp::Opcode::ArpReply,
-                    };
-                    let reply_data = arp::Ipv4Data {
-                        smac: self.netdev.hwaddr,
-                        sip: self.netdev.ipaddr,
-                        dmac: arp_data.smac,
-                        dip: arp_data.sip,
-                    };
-                    self.write_arp_packet(reply_hdr, reply_data).await?;
+    /// Fills in the Ethernet header of a packet sent by the application and writes it to
+    /// the device, or parks it until the destination's MAC address is known. Broadcast,
+    /// including the subnet's broadcast address, and multicast destinations map straight
+    /// to their MAC addresses.
+    async fn transmit(&mut self, packet: Packet<'pool, 'pool, PKT_POOL_SZ>) -> error::Result<()> {
+        let (ip_hdr, _) = ipv4::Header::decode(packet.as_slice())?;
+        if self.netdev.is_broadcast(ip_hdr.dst_addr) {
+            return self.write_ipv4_packet(packet, BROADCAST_MAC).await;
+        }
+        if ip_hdr.dst_addr.is_multicast() {
+            let dmac = eth::multicast_mac(ip_hdr.dst_addr);
+            return self.write_ipv4_packet(packet, dmac).await;
+        }
+        match self.arp_table.lookup(ip_hdr.dst_addr) {
+            Some(dmac) => self.write_ipv4_packet(packet, dmac).await,
+            None => {
+                let resolving = self
+                    .arp_waiters
+                    .iter()
+                    .any(|waiter| waiter.next_hop == ip_hdr.dst_addr);
+                packet.set_status(PacketStatus::WaitingForArp);
+                self.arp_waiters.push(ArpWaiter {
+                    packet,
+                    next_hop: ip_hdr.dst_addr,
+                    queued_at: Instant::now(),
+                });
+                if !resolving {
+                    self.send_arp_request(ip_hdr.dst_addr).await?;
                 }
+                Ok(())
             }
         }
+    }
+
+    async fn write_ipv4_packet(
+        &mut self,
+        mut packet: Packet<'pool, 'pool, PKT_POOL_SZ>,
+        dmac: [u8; 6],
+    ) -> error::Result<()> {
+        self.push_eth_header(&mut packet, dmac, eth::Ethertype::IPv4)?;
+        self.write_frame(packet.as_slice()).await?;
         Ok(())
     }
 
-    async fn write_arp_packet(&mut self, hdr: arp::Header, data: arp::Ipv4Data) -> io::Result<()> {
-        let mut buf = [0u8; 1500];
+    /// Puts an Ethernet header from this device to `dmac` in front of the packet data
+    fn push_eth_header(
+        &self,
+        packet: &mut Packet<'pool, 'pool, PKT_POOL_SZ>,
+        dmac: [u8; 6],
+        ethertype: eth::Ethertype,
+    ) -> error::Result<()> {
         let eth_hdr = eth::Header {
             smac: self.netdev.hwaddr,
-            dmac: data.dmac,
-            ethertype: eth::Ethertype::ARP,
+            dmac,
+            ethertype,
         };
-        let mut idx = eth_hdr.encode(&mut buf)?;
-        idx += hdr.encode(&mut buf[idx..])?;
-        idx += data.encode(&mut buf[idx..])?;
-        log::info!("ARP packet len: {}", idx);
-        let trailer_len = 18;
-        self.writer.write_all(&buf[..idx + trailer_len]).await?;
+        eth_hdr.encode(packet.push_header(eth::HEADER_SIZE)?)?;
         Ok(())
     }
 
-    fn do_arp_lookup(&self, ip_addr: Ipv4Addr) -> Option<[u8; 6]> {
-        if let Some(entry) = self.arp_translation_table.iter().find(|&&entry| {
-            if let Some(entry) = entry {
-                entry.ip == ip_addr
-            } else {
-                false
+    /// Sends the packets which were waiting for `ip_addr` to be resolved
+    async fn release_arp_waiters(&mut self, ip_addr: Ipv4Addr, mac: [u8; 6]) {
+        let (ready, waiting) = std::mem::take(&mut self.arp_waiters)
+            .into_iter()
+            .partition(|waiter| waiter.next_hop == ip_addr);
+        self.arp_waiters = waiting;
+        for waiter in ready {
+            waiter.packet.set_status(PacketStatus::ReadyToTransmit);
+            if let Err(err) = self.write_ipv4_packet(waiter.packet, mac).await {
+                log::error!("Error transmitting packet: {}", err);
+                self.record_error(&err);
+            }
+        }
+    }
+
+    /// Drops packets which have waited too long for their next hop to be resolved
+    fn expire_arp_waiters(&mut self) {
+        let stats = &self.stats;
+        self.arp_waiters.retain(|waiter| {
+            let expired = waiter.queued_at.elapsed() >= ARP_RESOLVE_TIMEOUT;
+            if expired {
+                log::warn!("No ARP reply from {}, dropping packet", waiter.next_hop);
+                stats.record_drop(DropReason::ArpMiss);
+            }
+            !expired
+        });
+    }
+
+    /// Processes a frame held in a pool packet. Each layer pulls its header off the
+    /// front of the packet before handing it up, and replies are written over the
+    /// received frame and sent from the same slot.
+    async fn handle_frame(&mut self, packet: &mut Packet<'pool, 'pool, PKT_POOL_SZ>) {
+        let (dmac, raw_ethertype) = match eth::EthernetFrame::new_checked(packet.as_slice()) {
+            Ok(frame) => (frame.dmac(), frame.ethertype()),
+            Err(err) => {
+                log::error!("Error handling frame: {}", err);
+                self.stats.record_frame_in(None);
+                self.record_error(&err);
+                return;
+            }
+        };
+        let ethertype = FromPrimitive::from_u16(raw_ethertype);
+        self.stats.record_frame_in(ethertype);
+        // Only the Ethernet header is read up front; the datagram is read when a filter
+        // chain or an ICMP error needs it
+        let info = filter::FrameInfo::from_link_header(packet.as_slice());
+        if !self.filter.is_empty(filter::Chain::Ingress) {
+            let datagram = &packet.as_slice()[eth::HEADER_SIZE..];
+            let ingress_info = filter::FrameInfo::from_frame(packet.as_slice());
+            if !self
+                .filter_frame(filter::Chain::Ingress, &ingress_info, datagram)
+                .await
+            {
+                return;
+            }
+        }
+        if !self.accepts_dmac(dmac) {
+            self.stats.record_drop(DropReason::NotForUs);
+            return;
+        }
+        // The frame was checked to be long enough for this
+        let _ = packet.pull_header(eth::HEADER_SIZE);
+        match ethertype {
+            Some(eth::Ethertype::ARP) => {
+                if let Err(err) = self.handle_arp(packet).await {
+                    log::error!("Error handling arp: {}", err);
+                    self.record_error(&err);
+                }
+            }
+            Some(eth::Ethertype::IPv4) => {
+                if let Err(err) = self.handle_ipv4(packet, &info).await {
+                    log::error!("Error handling ip: {}", err);
+                    self.record_error(&err);
+                }
+            }
+            Some(eth::Ethertype::RARP) => {
+                if let Err(err) = self.handle_rarp(packet).await {
+                    log::error!("Error handling rarp: {}", err);
+                    self.record_error(&err);
+                }
+            }
+            None => {
+                log::info!("Unhandled ethertype: {:#06x}", raw_ethertype);
             }
-        }) {
-            Some(entry.unwrap().mac)
-        } else {
-            None
         }
     }
 
-    async fn handle_ipv4(&mut self, packet: &[u8]) -> io::Result<()> {
-        let (hdr, ip_payload) = ipv4::Header::decode(packet)?;
-        if hdr.dst_addr == self.netdev.ipaddr {
-            if hdr.proto == ipv4::ProtocolType::IcmpV4 {
-                log::info!("Got a ping from {}", hdr.src_addr);
-                self.handle_icmpv4(hdr, ip_payload).await?;
+    /// Checks a frame against the rules in `chain`, and answers it with an ICMP error if
+    /// a rule rejects it. Returns whether the stack should go on handling the frame.
+    /// `datagram` is whatever follows the Ethernet header.
+    async fn filter_frame(
+        &mut self,
+        chain: filter::Chain,
+        info: &filter::FrameInfo,
+        datagram: &[u8],
+    ) -> bool {
+        if self.filter.is_empty(chain) {
+            return true;
+        }
+        let verdict = self.filter.check(chain, &self.netdev.name, info);
+        if verdict == filter::Verdict::Accept {
+            return true;
+        }
+        self.stats.record_drop(DropReason::Filtered);
+        // A promiscuous stack doesn't answer for frames sent to other hosts
+        let ours = info.dmac.is_some_and(|dmac| self.addressed_to_us(dmac));
+        if verdict == filter::Verdict::Reject && ours {
+            let message = Icmpv4Message::destination_unreachable(icmpv4::CODE_ADMIN_PROHIBITED);
+            if let Err(err) = self.send_icmp_error(info, datagram, message).await {
+                log::error!("Error rejecting frame: {}", err);
+                self.record_error(&err);
             }
         }
+        false
+    }
+
+    /// Tells the sender of an IPv4 datagram what became of it, e.g. that it was refused.
+    /// Nothing is sent about ICMP errors, fragments other than the first, or datagrams
+    /// which weren't from or to a single host (RFC 1122 3.2.2).
+    async fn send_icmp_error(
+        &mut self,
+        info: &filter::FrameInfo,
+        datagram: &[u8],
+        message: Icmpv4Message,
+    ) -> error::Result<()> {
+        let (src_addr, smac) = match (info.src_addr, info.smac) {
+            (Some(src_addr), Some(smac)) => (src_addr, smac),
+            _ => return Ok(()),
+        };
+        let ip_packet = ipv4::Ipv4Packet::new_checked(datagram)?;
+        let icmp_error = info.icmp_type.is_some_and(icmpv4::is_error);
+        let single_host = |addr: Ipv4Addr| {
+            !(addr.is_unspecified() || addr.is_multicast() || self.netdev.is_broadcast(addr))
+        };
+        if icmp_error
+            || ip_packet.fragment_offset() != 0
+            || smac[0] & 0x01 != 0
+            || !single_host(src_addr)
+            || !single_host(ip_packet.dst_addr())
+        {
+            return Ok(());
+        }
+
+        // The error holds the datagram's header and the first eight bytes of its payload
+        let quoted_len = std::cmp::min(ip_packet.header_len() + 8, ip_packet.total_len() as usize);
+        let mut packet = self.pkt_pool.allocate().ok_or(NettyError::PoolExhausted)?;
+        let result = self
+            .write_icmp_error(
+                &mut packet,
+                smac,
+                src_addr,
+                message,
+                &datagram[..quoted_len],
+            )
+            .await;
+        packet.discard();
+        result
+    }
+
+    async fn write_icmp_error(
+        &mut self,
+        packet: &mut Packet<'pool, 'pool, PKT_POOL_SZ>,
+        dmac: [u8; 6],
+        dst_addr: Ipv4Addr,
+        message: Icmpv4Message,
+        quoted: &[u8],
+    ) -> error::Result<()> {
+        packet.reserve(DEFAULT_HEADROOM)?;
+        packet.put(quoted.len())?.copy_from_slice(quoted);
+        PacketBuilder::ethernet(self.netdev.hwaddr, dmac)
+            .ipv4(self.netdev.ipaddr, dst_addr)
+            .icmpv4(message)
+            .finalize(packet)?;
+        self.write_frame(packet.as_slice()).await?;
         Ok(())
     }
 
-    async fn handle_icmpv4(&mut self, mut ip_hdr: ipv4::Header, ip_data: &[u8]) -> io::Result<()> {
-        let (icmp_hdr, payload) = icmpv4::Header::decode(ip_data)?;
-        if icmp_hdr.msg_type == icmpv4::MsgType::EchoRequest {
-            let (echo_hdr, _payload) = icmpv4::EchoHeader::decode(payload)?;
-            let echo_reply_hdr = icmpv4::EchoHeader {
-                id: echo_hdr.id,
-                seq: echo_hdr.seq,
+    /// Counts a frame dropped because handling it failed
+    fn record_error(&self, err: &NettyError) {
+        if let Some(reason) = err.drop_reason() {
+            self.stats.record_drop(reason);
+        }
+    }
+
+    async fn handle_arp(
+        &mut self,
+        packet: &mut Packet<'pool, 'pool, PKT_POOL_SZ>,
+    ) -> error::Result<()> {
+        let (hdr, arp_payload) = arp::Header::decode(packet.as_slice())?;
+        if hdr.hwtype != arp::HwType::Ethernet || hdr.protype != arp::ProtocolType::Ipv4 {
+            return Ok(());
+        }
+        let (arp_data, _remainder) = arp::Ipv4Data::decode(arp_payload)?;
+        match hdr.opcode {
+            arp::Opcode::ArpRequest => self.stats.record_arp_in(true),
+            arp::Opcode::ArpReply => self.stats.record_arp_in(false),
+            _ => {}
+        }
+        if !self.within_arp_rate_limit(arp_data.smac) {
+            log::warn!("Too many ARP packets from {:x?}, dropping", arp_data.smac);
+            self.stats.record_drop(DropReason::RateLimited);
+            return Ok(());
+        }
+
+        // Any sender already in the table is refreshed, but a new one is only
+        // added if it's asking us (RFC 826), answering a request we sent, or the
+        // policy learns from packets for other hosts too
+        let for_us = self.answers_arp_for(arp_data.sip, arp_data.dip);
+        let heard = for_us || self.arp_policy.learn_overheard;
+        let create = match hdr.opcode {
+            arp::Opcode::ArpRequest => heard,
+            arp::Opcode::ArpReply => {
+                (heard && self.arp_policy.accept_unsolicited_replies)
+                    || self
+                        .arp_waiters
+                        .iter()
+                        .any(|waiter| waiter.next_hop == arp_data.sip)
+            }
+            _ => false,
+        };
+        if !arp_data.sip.is_unspecified() {
+            let learnt = self.arp_table.learn(
+                arp_data.sip,
+                arp_data.smac,
+                create,
+                self.arp_policy.lock_time,
+            );
+            if let Learnt::Conflict { .. } = learnt {
+                self.stats.record_arp_conflict();
+            }
+        }
+
+        // Is it an ARP request for us, or for an address we're a proxy for?
+        if hdr.opcode == arp::Opcode::ArpRequest && for_us {
+            let reply_hdr = arp::Header {
+                hwtype: arp::HwType::Ethernet,
+                protype: arp::ProtocolType::Ipv4,
+                hwsize: 6,
+                prosize: 4,
+                opcode: arp::Opcode::ArpReply,
             };
-            let mut buf = [0u8; 1500];
-            let end_payload_start = eth::HEADER_SIZE
-                + ipv4::HEADER_SIZE
-                + icmpv4::HEADER_SIZE
-                + icmpv4::ECHO_HEADER_SIZE;
-            let mut cursor = io::Cursor::new(&mut buf[end_payload_start..]);
-            cursor.write_all(_payload).await?;
-            echo_reply_hdr.encode(&mut buf[end_payload_start - icmpv4::ECHO_HEADER_SIZE..])?;
-
-            let mut icmp_hdr = icmpv4::Header {
-                msg_type: icmpv4::MsgType::EchoReply,
-                code: 0,
-                checksum: 0,
+            let reply_data = arp::Ipv4Data {
+                smac: self.netdev.hwaddr,
+                sip: arp_data.dip,
+                dmac: arp_data.smac,
+                dip: arp_data.sip,
             };
-            let ip_payload_start = eth::HEADER_SIZE + ipv4::HEADER_SIZE;
-            let _ = icmp_hdr.clone().encode(&mut buf[ip_payload_start..])?;
-            let checksum =
-                util::checksum(&buf[ip_payload_start..end_payload_start + _payload.len()]);
-            icmp_hdr.checksum = checksum;
-            icmp_hdr.encode(&mut buf[ip_payload_start..])?;
-
-            ip_hdr.datagram_len = (icmpv4::ECHO_HEADER_SIZE
-                + icmpv4::HEADER_SIZE
-                + ipv4::HEADER_SIZE
-                + _payload.len()) as u16;
-            ip_hdr.checksum = 0;
-            ip_hdr.dst_addr = ip_hdr.src_addr;
-            ip_hdr.src_addr = self.netdev.ipaddr;
-            let eth_payload_start = eth::HEADER_SIZE;
-            let _ = ip_hdr.encode(&mut buf[eth_payload_start..])?;
-            ip_hdr.checksum = util::checksum(&buf[eth_payload_start..ip_payload_start]);
-            ip_hdr.encode(&mut buf[eth_payload_start..])?;
-
-            if let Some(dmac) = self.do_arp_lookup(ip_hdr.dst_addr) {
-                let eth_hdr = eth::Header {
+            // The request is no longer needed, so the reply can take over its slot
+            self.write_arp_packet(packet, arp_data.smac, reply_hdr, reply_data)
+                .await?;
+        }
+
+        // Waiting packets go to whichever MAC address the table settled on
+        let mac = self.arp_table.lookup(arp_data.sip).unwrap_or(arp_data.smac);
+        self.release_arp_waiters(arp_data.sip, mac).await;
+        Ok(())
+    }
+
+    /// Broadcasts a request for the MAC address of `ip_addr`
+    async fn send_arp_request(&mut self, ip_addr: Ipv4Addr) -> error::Result<()> {
+        let mut packet = self.pkt_pool.allocate().ok_or(NettyError::PoolExhausted)?;
+        let request_hdr = arp::Header {
+            hwtype: arp::HwType::Ethernet,
+            protype: arp::ProtocolType::Ipv4,
+            hwsize: 6,
+            prosize: 4,
+            opcode: arp::Opcode::ArpRequest,
+        };
+        let request_data = arp::Ipv4Data {
+            smac: self.netdev.hwaddr,
+            sip: self.netdev.ipaddr,
+            dmac: [0; 6],
+            dip: ip_addr,
+        };
+        let result = self
+            .write_arp_packet(&mut packet, BROADCAST_MAC, request_hdr, request_data)
+            .await;
+        packet.discard();
+        result
+    }
+
+    /// Takes one packet from the allowance of the host at `smac`. Once
+    /// `ARP_RATE_LIMIT_SOURCES` hosts are tracked, a new one only gets an allowance if an
+    /// idle one can be forgotten.
+    fn within_arp_rate_limit(&mut self, smac: [u8; 6]) -> bool {
+        let rate = match self.arp_policy.rate_limit {
+            Some(rate) => rate,
+            None => return true,
+        };
+        if self.arp_rate_limits.len() >= ARP_RATE_LIMIT_SOURCES
+            && !self.arp_rate_limits.contains_key(&smac)
+        {
+            // Sources which have gone quiet can be forgotten to make room
+            self.arp_rate_limits.retain(|_, bucket| !bucket.is_full());
+            if self.arp_rate_limits.len() >= ARP_RATE_LIMIT_SOURCES {
+                return false;
+            }
+        }
+        self.arp_rate_limits
+            .entry(smac)
+            .or_insert_with(|| util::TokenBucket::new(rate as f64, rate as f64))
+            .try_take(1.0)
+    }
+
+    /// Whether to answer a request from `sip` for the MAC address of `dip`: either it's
+    /// this device's address, or it's behind the stack in one of the proxy ARP prefixes.
+    /// A host probing for or announcing its own address is never answered for.
+    fn answers_arp_for(&self, sip: Ipv4Addr, dip: Ipv4Addr) -> bool {
+        if dip == self.netdev.ipaddr {
+            return !dip.is_unspecified();
+        }
+        dip != sip
+            && !sip.is_unspecified()
+            && self
+                .proxy_arp_prefixes
+                .iter()
+                .any(|prefix| prefix.contains(dip) && !prefix.contains(sip))
+    }
+
+    /// Broadcasts a request for this device's own IPv4 address
+    async fn send_rarp_request(&mut self) -> error::Result<()> {
+        let mut packet = self.pkt_pool.allocate().ok_or(NettyError::PoolExhausted)?;
+        let request_hdr = arp::Header {
+            hwtype: arp::HwType::Ethernet,
+            protype: arp::ProtocolType::Ipv4,
+            hwsize: 6,
+            prosize: 4,
+            opcode: arp::Opcode::RarpRequest,
+        };
+        let request_data = arp::Ipv4Data {
+            smac: self.netdev.hwaddr,
+            sip: Ipv4Addr::UNSPECIFIED,
+            dmac: self.netdev.hwaddr,
+            dip: Ipv4Addr::UNSPECIFIED,
+        };
+        let result = self
+            .write_arp_packet(&mut packet, BROADCAST_MAC, request_hdr, request_data)
+            .await;
+        packet.discard();
+        result
+    }
+
+    /// Answers RARP requests for clients in the RARP table, and learns this device's
+    /// address from a reply while it doesn't have one
+    async fn handle_rarp(
+        &mut self,
+        packet: &mut Packet<'pool, 'pool, PKT_POOL_SZ>,
+    ) -> error::Result<()> {
+        let (hdr, rarp_payload) = arp::Header::decode(packet.as_slice())?;
+        if hdr.hwtype != arp::HwType::Ethernet || hdr.protype != arp::ProtocolType::Ipv4 {
+            return Ok(());
+        }
+        let (rarp_data, _) = arp::Ipv4Data::decode(rarp_payload)?;
+        match hdr.opcode {
+            arp::Opcode::RarpRequest => {
+                let ipaddr = match self.rarp_table.get(&rarp_data.dmac) {
+                    Some(&ipaddr) if !self.netdev.ipaddr.is_unspecified() => ipaddr,
+                    _ => return Ok(()),
+                };
+                log::info!("RARP: {:x?} is {}", rarp_data.dmac, ipaddr);
+                let reply_hdr = arp::Header {
+                    opcode: arp::Opcode::RarpReply,
+                    ..hdr
+                };
+                let reply_data = arp::Ipv4Data {
                     smac: self.netdev.hwaddr,
-                    dmac,
-                    ethertype: eth::Ethertype::IPv4,
+                    sip: self.netdev.ipaddr,
+                    dmac: rarp_data.dmac,
+                    dip: ipaddr,
+                };
+                self.write_arp_packet(packet, rarp_data.smac, reply_hdr, reply_data)
+                    .await?;
+            }
+            arp::Opcode::RarpReply
+                if self.netdev.ipaddr.is_unspecified() && rarp_data.dmac == self.netdev.hwaddr =>
+            {
+                log::info!(
+                    "RARP: learnt address {} from {}",
+                    rarp_data.dip,
+                    rarp_data.sip
+                );
+                self.netdev.ipaddr = rarp_data.dip;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Encodes an ARP or RARP packet into `packet`, replacing its contents, and sends it
+    /// to `dmac`
+    async fn write_arp_packet(
+        &mut self,
+        packet: &mut Packet<'pool, 'pool, PKT_POOL_SZ>,
+        dmac: [u8; 6],
+        hdr: arp::Header,
+        data: arp::Ipv4Data,
+    ) -> error::Result<()> {
+        packet.reserve(DEFAULT_HEADROOM)?;
+        hdr.encode(packet.put(arp::HEADER_SIZE)?)?;
+        data.encode(packet.put(arp::IPV4_DATA_SIZE)?)?;
+        let trailer_len = 18;
+        packet.put(trailer_len)?.fill(0);
+        let ethertype = match hdr.opcode {
+            arp::Opcode::RarpRequest | arp::Opcode::RarpReply => eth::Ethertype::RARP,
+            arp::Opcode::ArpRequest | arp::Opcode::ArpReply => eth::Ethertype::ARP,
+        };
+        self.push_eth_header(packet, dmac, ethertype)?;
+        log::info!("ARP packet len: {}", packet.len());
+        self.write_frame(packet.as_slice()).await?;
+        Ok(())
+    }
+
+    /// Writes a complete Ethernet frame to the device and counts it, unless the egress
+    /// filter drops it. IPv4 datagrams are recorded by the connection tracker once the
+    /// frame is on its way. While egress shaping is enabled the frame is queued, and only
+    /// written now if the rate limit allows.
+    async fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        let ipv4 = eth::EthernetFrame::new_checked(frame)
+            .is_ok_and(|eth_frame| eth_frame.ethertype() == eth::Ethertype::IPv4 as u16);
+        if !self.filter.is_empty(filter::Chain::Output) {
+            let mut info = filter::FrameInfo::from_frame(frame);
+            if ipv4 {
+                info.conn_state = self.conntrack.lookup(&frame[eth::HEADER_SIZE..]);
+            }
+            let verdict = self
+                .filter
+                .check(filter::Chain::Output, &self.netdev.name, &info);
+            if verdict != filter::Verdict::Accept {
+                self.stats.record_drop(DropReason::Filtered);
+                return Ok(());
+            }
+        }
+        if let Some(shaper) = self.shaper.as_mut() {
+            let queue = shaper::priority(frame);
+            if !shaper.enqueue(queue, frame) {
+                log::warn!("Egress queue {} is full, dropping frame", queue);
+                self.stats.record_egress_drop(queue);
+                return Ok(());
+            }
+        }
+        if ipv4 {
+            self.conntrack.track(&frame[eth::HEADER_SIZE..]);
+        }
+        match self.shaper {
+            Some(_) => self.send_queued().await,
+            None => self.write_to_device(frame).await,
+        }
+    }
+
+    /// Writes the queued frames which the egress rate limit allows to be sent now
+    async fn send_queued(&mut self) -> io::Result<()> {
+        while let Some((queue, frame)) = self.shaper.as_mut().and_then(shaper::Shaper::dequeue) {
+            self.write_to_device(&frame).await?;
+            self.stats.record_egress_sent(queue, frame.len());
+        }
+        Ok(())
+    }
+
+    /// When the next queued frame may be sent, if egress shaping has any waiting
+    fn egress_deadline(&mut self) -> Option<Instant> {
+        self.shaper.as_mut().and_then(shaper::Shaper::next_send_at)
+    }
+
+    async fn write_to_device(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.writer.write_all(frame).await?;
+        self.record_frame_sent(frame);
+        Ok(())
+    }
+
+    /// Counts a frame once it has been written, along with the ARP or ICMP message it
+    /// carries
+    fn record_frame_sent(&self, frame: &[u8]) {
+        let (eth_hdr, payload) = match eth::Header::decode(frame) {
+            Ok(decoded) => decoded,
+            Err(_) => return self.stats.record_frame_out(None),
+        };
+        self.stats.record_frame_out(Some(eth_hdr.ethertype));
+        match eth_hdr.ethertype {
+            eth::Ethertype::ARP => {
+                if let Ok(arp_packet) = arp::ArpPacket::new_checked(payload) {
+                    let request = arp_packet.opcode() == arp::Opcode::ArpRequest as u16;
+                    self.stats.record_arp_out(request);
+                }
+            }
+            eth::Ethertype::IPv4 => {
+                let ip_packet = match ipv4::Ipv4Packet::new_checked(payload) {
+                    Ok(ip_packet) => ip_packet,
+                    Err(_) => return,
                 };
-                let _ = eth_hdr.encode(&mut buf[0..])?;
-                self.writer
-                    .write_all(&buf[..end_payload_start + _payload.len()])
+                // Only the first fragment holds the ICMP header
+                if ip_packet.protocol() == ipv4::ProtocolType::IcmpV4 as u8
+                    && ip_packet.fragment_offset() == 0
+                {
+                    if let Some(&msg_type) = ip_packet.payload().first() {
+                        self.stats.record_icmp_out(msg_type);
+                    }
+                }
+            }
+            eth::Ethertype::RARP => {}
+        }
+    }
+
+    async fn handle_ipv4(
+        &mut self,
+        packet: &mut Packet<'pool, 'pool, PKT_POOL_SZ>,
+        info: &filter::FrameInfo,
+    ) -> error::Result<()> {
+        let ip_packet = ipv4::Ipv4Packet::new_checked(packet.as_slice())?;
+        self.check_rx_checksum(error::Layer::Ipv4, || ip_packet.verify_checksum())?;
+        let header_len = ip_packet.header_len();
+        let total_len = ip_packet.total_len() as usize;
+        let proto = ip_packet.protocol();
+        let src_addr = ip_packet.src_addr();
+        let dst_addr = ip_packet.dst_addr();
+        let time_to_live = ip_packet.time_to_live();
+        self.stats.record_ipv4_in(proto);
+
+        // Anything past the datagram length is Ethernet padding
+        packet.trim(total_len);
+        let proto = FromPrimitive::from_u8(proto);
+        let igmp = proto == Some(ipv4::ProtocolType::Igmp)
+            && (dst_addr == self.netdev.ipaddr || dst_addr.is_multicast());
+        let local = if dst_addr.is_multicast() {
+            igmp || self.multicast.is_member(dst_addr)
+        } else {
+            dst_addr == self.netdev.ipaddr || self.netdev.is_broadcast(dst_addr)
+        };
+
+        // Datagrams between inside hosts and the outside are translated and passed on.
+        // One which has run out of hops is answered with a time exceeded instead.
+        let outside_addr = self.netdev.ipaddr;
+        if info.dmac == Some(self.netdev.hwaddr) && !outside_addr.is_unspecified() {
+            // A datagram which has run out of hops mustn't add or refresh a mapping
+            let expired = time_to_live <= 1;
+            let datagram = packet.as_mut_slice();
+            let translated = match (dst_addr == outside_addr, expired) {
+                (true, true) => self.nat.translates_inbound(datagram, outside_addr),
+                (true, false) => self.nat.translate_inbound(datagram, outside_addr),
+                (false, _) if local => false,
+                (false, true) => self.nat.translates_outbound(datagram, outside_addr),
+                (false, false) => self.nat.translate_outbound(datagram, outside_addr),
+            };
+            if translated && expired {
+                self.stats.record_drop(DropReason::TtlExpired);
+                let info = info.with_datagram(packet.as_slice());
+                let message = Icmpv4Message::time_exceeded(icmpv4::CODE_TTL_EXCEEDED);
+                return self
+                    .send_icmp_error(&info, packet.as_slice(), message)
+                    .await;
+            }
+            if translated {
+                return self.forward(packet.as_slice()).await;
+            }
+        }
+
+        if !local {
+            self.stats.record_drop(DropReason::NotForUs);
+            return Ok(());
+        }
+        if !self.filter.is_empty(filter::Chain::Input) {
+            let info = filter::FrameInfo {
+                conn_state: self.conntrack.lookup(packet.as_slice()),
+                ..info.with_datagram(packet.as_slice())
+            };
+            if !self
+                .filter_frame(filter::Chain::Input, &info, packet.as_slice())
+                .await
+            {
+                return Ok(());
+            }
+        }
+        self.conntrack.track(packet.as_slice());
+
+        if igmp {
+            packet.pull_header(header_len)?;
+            self.handle_igmp(packet).await?;
+        } else if dst_addr.is_multicast() {
+            if proto == Some(ipv4::ProtocolType::Udp) {
+                let udp_packet = udp::UdpPacket::new_checked(&packet.as_slice()[header_len..])?;
+                self.check_rx_checksum(error::Layer::Udp, || {
+                    udp_packet.verify_checksum(src_addr, dst_addr)
+                })?;
+            }
+            self.multicast
+                .deliver(dst_addr, packet.as_slice(), self.pkt_pool)?;
+        } else {
+            packet.pull_header(header_len)?;
+            if let Some(ipv4::ProtocolType::IcmpV4) = proto {
+                log::info!("Got a ping from {}", src_addr);
+                let broadcast = dst_addr != self.netdev.ipaddr;
+                self.handle_icmpv4(header_len, src_addr, broadcast, packet)
                     .await?;
             }
         }
         Ok(())
     }
+
+    /// Sends on a datagram which isn't for the stack, with its TTL decremented. One which
+    /// would arrive with a TTL of zero is dropped.
+    async fn forward(&mut self, datagram: &[u8]) -> error::Result<()> {
+        let ip_packet = ipv4::Ipv4Packet::new_checked(datagram)?;
+        let time_to_live = match ip_packet.time_to_live().checked_sub(1) {
+            Some(time_to_live) if time_to_live > 0 => time_to_live,
+            _ => {
+                self.stats.record_drop(DropReason::TtlExpired);
+                return Ok(());
+            }
+        };
+        let mut packet = self.pkt_pool.allocate().ok_or(NettyError::PoolExhausted)?;
+        if let Err(err) = packet.put(datagram.len()) {
+            packet.discard();
+            return Err(err.into());
+        }
+        packet.as_mut_slice().copy_from_slice(datagram);
+        // It goes out like any packet the stack sends, not one it has just allocated
+        packet.set_status(PacketStatus::ReadyToTransmit);
+        let mut ip_packet = ipv4::Ipv4Packet::new_unchecked(packet.as_mut_slice());
+        let old_word = u16::from_be_bytes([ip_packet.time_to_live(), ip_packet.protocol()]);
+        let new_word = u16::from_be_bytes([time_to_live, ip_packet.protocol()]);
+        ip_packet.set_time_to_live(time_to_live);
+        ip_packet.set_checksum(util::update_word(ip_packet.checksum(), old_word, new_word));
+        self.transmit(packet).await
+    }
+
+    /// Whether a frame sent to `dmac` should be handled: it must be for this device, a
+    /// broadcast or for a multicast group the stack is a member of, unless the device is
+    /// promiscuous.
+    fn accepts_dmac(&self, dmac: [u8; 6]) -> bool {
+        self.netdev.promiscuous || self.addressed_to_us(dmac)
+    }
+
+    /// Whether `dmac` is this device's address, the broadcast address or that of a
+    /// multicast group the stack is a member of
+    fn addressed_to_us(&self, dmac: [u8; 6]) -> bool {
+        if dmac == self.netdev.hwaddr || dmac == BROADCAST_MAC {
+            return true;
+        }
+        // The group bit of the first octet marks multicast addresses
+        dmac[0] & 0x01 != 0
+            && (dmac == eth::multicast_mac(igmp::ALL_SYSTEMS) || self.multicast.accepts_mac(dmac))
+    }
+
+    /// Runs `verify` on a received header unless the device offloads checksum checks.
+    fn check_rx_checksum(
+        &self,
+        layer: error::Layer,
+        verify: impl FnOnce() -> bool,
+    ) -> error::Result<()> {
+        if self.netdev.rx_checksum_offload || verify() {
+            Ok(())
+        } else {
+            Err(NettyError::BadChecksum { layer })
+        }
+    }
+
+    /// Handles an ICMP message whose IPv4 header of `ip_header_len` bytes has been
+    /// pulled off the front of `packet`. Echo requests sent to a broadcast address
+    /// aren't answered, so the stack can't be used to amplify traffic.
+    async fn handle_icmpv4(
+        &mut self,
+        ip_header_len: usize,
+        src_addr: Ipv4Addr,
+        broadcast: bool,
+        packet: &mut Packet<'pool, 'pool, PKT_POOL_SZ>,
+    ) -> error::Result<()> {
+        let mut icmp_packet = icmpv4::Icmpv4Packet::new_checked(packet.as_mut_slice())?;
+        self.check_rx_checksum(error::Layer::Icmpv4, || icmp_packet.verify_checksum())?;
+        self.stats.record_icmp_in(icmp_packet.msg_type());
+        if icmp_packet.msg_type() == icmpv4::MsgType::EchoRequest as u8 && !broadcast {
+            log::info!(
+                "Echo request id {} seq {}",
+                icmp_packet.echo_id(),
+                icmp_packet.echo_seq()
+            );
+            let dmac = match self.arp_table.lookup(src_addr) {
+                Some(dmac) => dmac,
+                None => return Err(NettyError::ArpMiss(src_addr)),
+            };
+
+            // The request's headers are still in the headroom, so the reply is made by
+            // rewriting them where they are
+            icmp_packet.set_msg_type(icmpv4::MsgType::EchoReply as u8);
+            icmp_packet.fill_checksum();
+
+            let mut ip_packet = ipv4::Ipv4Packet::new_unchecked(packet.push_header(ip_header_len)?);
+            ip_packet.set_dst_addr(src_addr);
+            ip_packet.set_src_addr(self.netdev.ipaddr);
+            ip_packet.fill_checksum();
+
+            let mut frame =
+                eth::EthernetFrame::new_unchecked(packet.push_header(eth::HEADER_SIZE)?);
+            frame.set_dmac(dmac);
+            frame.set_smac(self.netdev.hwaddr);
+            self.write_frame(packet.as_slice()).await?;
+        }
+        Ok(())
+    }
+
+    /// Handles an IGMP message whose IPv4 header has been pulled off the front of `packet`
+    async fn handle_igmp(
+        &mut self,
+        packet: &mut Packet<'pool, 'pool, PKT_POOL_SZ>,
+    ) -> error::Result<()> {
+        let igmp_packet = igmp::IgmpPacket::new_checked(packet.as_slice())?;
+        self.check_rx_checksum(error::Layer::Igmp, || igmp_packet.verify_checksum())?;
+        let group = igmp_packet.group_addr();
+        match FromPrimitive::from_u8(igmp_packet.msg_type()) {
+            Some(igmp::MsgType::MembershipQuery) => {
+                let now = Instant::now();
+                if !igmp_packet.is_v3_query() {
+                    self.igmp.v2_querier_until = Some(now + OLDER_QUERIER_TIMEOUT);
+                }
+                // A general query asks about every group, otherwise only the one given
+                let groups = match group.is_unspecified() {
+                    true => self.multicast.groups(),
+                    false if self.multicast.is_member(group) => vec![group],
+                    false => Vec::new(),
+                };
+                let due = now + report_delay(&mut self.igmp.rng, igmp_packet.max_resp_time());
+                for group in groups
+                    .into_iter()
+                    .filter(|&group| group != igmp::ALL_SYSTEMS)
+                {
+                    match self
+                        .igmp
+                        .pending_reports
+                        .iter_mut()
+                        .find(|(g, _)| *g == group)
+                    {
+                        Some((_, pending_due)) => *pending_due = std::cmp::min(*pending_due, due),
+                        None => self.igmp.pending_reports.push((group, due)),
+                    }
+                }
+            }
+            // Another member already answered, so an IGMPv2 host keeps quiet
+            Some(igmp::MsgType::V1MembershipReport) | Some(igmp::MsgType::V2MembershipReport)
+                if self.igmp_v2_mode() =>
+            {
+                self.igmp.pending_reports.retain(|(g, _)| *g != group);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Whether an IGMPv2 router has been heard from recently
+    fn igmp_v2_mode(&self) -> bool {
+        self.igmp
+            .v2_querier_until
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    /// Sends reports for groups which were joined or left since the last call
+    async fn update_memberships(&mut self) {
+        let groups: Vec<_> = self
+            .multicast
+            .groups()
+            .into_iter()
+            .filter(|&group| group != igmp::ALL_SYSTEMS)
+            .collect();
+        let reported = std::mem::replace(&mut self.igmp.reported, groups.clone());
+        for &group in groups.iter().filter(|group| !reported.contains(group)) {
+            log::info!("Joining multicast group {}", group);
+            self.send_state_change(group, igmp::RecordType::ChangeToExclude)
+                .await;
+        }
+        for &group in reported.iter().filter(|group| !groups.contains(group)) {
+            log::info!("Leaving multicast group {}", group);
+            self.igmp.pending_reports.retain(|(g, _)| *g != group);
+            self.send_state_change(group, igmp::RecordType::ChangeToInclude)
+                .await;
+        }
+    }
+
+    /// Sends a join or leave report for `group`, and schedules its repeats in place of
+    /// any left from an earlier change
+    async fn send_state_change(&mut self, group: Ipv4Addr, record_type: igmp::RecordType) {
+        self.igmp
+            .state_changes
+            .retain(|change| change.group != group);
+        if IGMP_ROBUSTNESS > 1 {
+            let interval = self.unsolicited_interval();
+            let due = Instant::now() + report_delay(&mut self.igmp.rng, interval);
+            self.igmp.state_changes.push(StateChange {
+                group,
+                record_type,
+                due,
+                remaining: IGMP_ROBUSTNESS - 1,
+            });
+        }
+        if let Err(err) = self.send_igmp_report(group, record_type).await {
+            log::error!("Error sending IGMP report: {}", err);
+            self.record_error(&err);
+        }
+    }
+
+    fn unsolicited_interval(&self) -> Duration {
+        match self.igmp_v2_mode() {
+            true => V2_UNSOLICITED_REPORT_INTERVAL,
+            false => UNSOLICITED_REPORT_INTERVAL,
+        }
+    }
+
+    /// Answers queries whose report delay has run out, and repeats join and leave
+    /// reports which are due
+    async fn send_due_igmp_reports(&mut self) {
+        let now = Instant::now();
+        let (due, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.igmp.pending_reports)
+            .into_iter()
+            .partition(|&(_, due)| due <= now);
+        self.igmp.pending_reports = pending;
+        let mut reports: Vec<_> = due
+            .into_iter()
+            .map(|(group, _)| (group, igmp::RecordType::ModeIsExclude))
+            .collect();
+
+        let interval = self.unsolicited_interval();
+        let igmp = &mut self.igmp;
+        for change in igmp
+            .state_changes
+            .iter_mut()
+            .filter(|change| change.due <= now)
+        {
+            reports.push((change.group, change.record_type));
+            change.remaining -= 1;
+            change.due = now + report_delay(&mut igmp.rng, interval);
+        }
+        igmp.state_changes.retain(|change| change.remaining > 0);
+
+        for (group, record_type) in reports {
+            if let Err(err) = self.send_igmp_report(group, record_type).await {
+                log::error!("Error sending IGMP report: {}", err);
+                self.record_error(&err);
+            }
+        }
+    }
+
+    /// Reports the stack's membership of `group`. IGMPv2 has no records, so a record
+    /// type which leaves the group becomes a leave message and any other is a report.
+    async fn send_igmp_report(
+        &mut self,
+        group: Ipv4Addr,
+        record_type: igmp::RecordType,
+    ) -> error::Result<()> {
+        let mut packet = self.pkt_pool.allocate().ok_or(NettyError::PoolExhausted)?;
+        let result = self
+            .write_igmp_report(&mut packet, group, record_type)
+            .await;
+        packet.discard();
+        result
+    }
+
+    async fn write_igmp_report(
+        &mut self,
+        packet: &mut Packet<'pool, 'pool, PKT_POOL_SZ>,
+        group: Ipv4Addr,
+        record_type: igmp::RecordType,
+    ) -> error::Result<()> {
+        packet.reserve(DEFAULT_HEADROOM)?;
+        let dst_addr = if self.igmp_v2_mode() {
+            let (msg_type, dst_addr) = match record_type {
+                igmp::RecordType::ChangeToInclude => (igmp::MsgType::LeaveGroup, igmp::ALL_ROUTERS),
+                _ => (igmp::MsgType::V2MembershipReport, group),
+            };
+            igmp::encode_v2_message(msg_type, group, packet.put(igmp::HEADER_SIZE)?)?;
+            dst_addr
+        } else {
+            let buf = packet.put(igmp::v3_report_len(1))?;
+            igmp::encode_v3_report(&[(record_type, group)], buf)?;
+            igmp::ALL_V3_ROUTERS
+        };
+        PacketBuilder::ethernet(self.netdev.hwaddr, eth::multicast_mac(dst_addr))
+            .ipv4(self.netdev.ipaddr, dst_addr)
+            .time_to_live(1)
+            .router_alert()
+            .payload(ipv4::ProtocolType::Igmp)
+            .finalize(packet)?;
+        self.write_frame(packet.as_slice()).await?;
+        Ok(())
+    }
+}
+
+/// Picks a random point before `max_resp_time` to answer a query at, so that members
+/// of a group don't all answer at once
+fn report_delay(rng: &mut util::Rng, max_resp_time: Duration) -> Duration {
+    max_resp_time.mul_f64(rng.unit())
 }
 
-pub struct NettyDevice<'a> {
-    name: &'a str,
+pub struct NettyDevice {
+    name: String,
     hwaddr: [u8; 6],
     ipaddr: Ipv4Addr,
+    netmask: Ipv4Addr,
+    /// Frames are handled whatever their destination MAC address
+    promiscuous: bool,
+    /// Received checksums were verified by the device
+    rx_checksum_offload: bool,
+}
+
+impl NettyDevice {
+    /// Whether `addr` is the limited broadcast address or the broadcast address of this
+    /// device's subnet
+    fn is_broadcast(&self, addr: Ipv4Addr) -> bool {
+        let directed = u32::from(self.ipaddr) | !u32::from(self.netmask);
+        addr == Ipv4Addr::BROADCAST || u32::from(addr) == directed
+    }
+}
+
+mod tests {
+    /// Builds an ARP frame, or a RARP frame for the RARP opcodes, from `data.smac` to `dmac`
+    #[cfg(test)]
+    fn arp_frame(dmac: [u8; 6], opcode: crate::arp::Opcode, data: crate::arp::Ipv4Data) -> Vec<u8> {
+        use crate::*;
+        let ethertype = match opcode {
+            arp::Opcode::RarpRequest | arp::Opcode::RarpReply => eth::Ethertype::RARP,
+            _ => eth::Ethertype::ARP,
+        };
+        let mut buf = vec![0u8; eth::HEADER_SIZE + arp::HEADER_SIZE + arp::IPV4_DATA_SIZE];
+        let mut frame = eth::EthernetFrame::new_unchecked(&mut buf[..]);
+        frame.set_dmac(dmac);
+        frame.set_smac(data.smac);
+        frame.set_ethertype(ethertype as u16);
+        let mut arp_packet = arp::ArpPacket::new_unchecked(frame.payload_mut());
+        arp_packet.set_hwtype(arp::HwType::Ethernet as u16);
+        arp_packet.set_protype(arp::ProtocolType::Ipv4 as u16);
+        arp_packet.set_hwsize(6);
+        arp_packet.set_prosize(4);
+        arp_packet.set_opcode(opcode as u16);
+        arp_packet.set_smac(data.smac);
+        arp_packet.set_sip(data.sip);
+        arp_packet.set_dmac(data.dmac);
+        arp_packet.set_dip(data.dip);
+        buf
+    }
+
+    #[tokio::test]
+    async fn answers_arp_and_ping() {
+        use crate::*;
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE * 4]));
+        let pool = PacketPool::<4>::new(buf).unwrap();
+        let (device, mut handle) = MemoryDevice::new();
+        let mut stack = NettyStack::with_device(device, &pool);
+        let host_mac = [0x02, 0, 0, 0, 0, 0x01];
+        let host_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let stack_mac = [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+        let stack_ip = Ipv4Addr::new(10, 0, 0, 2);
+
+        let request = arp::Ipv4Data {
+            smac: host_mac,
+            sip: host_ip,
+            dmac: [0; 6],
+            dip: stack_ip,
+        };
+        handle.inject(&arp_frame(BROADCAST_MAC, arp::Opcode::ArpRequest, request));
+
+        let mut ping = [0u8; eth::HEADER_SIZE + ipv4::HEADER_SIZE + 8 + 4];
+        let mut frame = eth::EthernetFrame::new_unchecked(&mut ping[..]);
+        frame.set_dmac(stack_mac);
+        frame.set_smac(host_mac);
+        frame.set_ethertype(eth::Ethertype::IPv4 as u16);
+        let ip_payload = frame.payload_mut();
+        ip_payload[0] = 0x45;
+        let mut ip_packet = ipv4::Ipv4Packet::new_unchecked(ip_payload);
+        ip_packet.set_total_len((ipv4::HEADER_SIZE + 8 + 4) as u16);
+        ip_packet.set_time_to_live(64);
+        ip_packet.set_protocol(ipv4::ProtocolType::IcmpV4 as u8);
+        ip_packet.set_src_addr(host_ip);
+        ip_packet.set_dst_addr(stack_ip);
+        ip_packet.fill_checksum();
+        let mut icmp_packet = icmpv4::Icmpv4Packet::new_unchecked(ip_packet.payload_mut());
+        icmp_packet.set_msg_type(icmpv4::MsgType::EchoRequest as u8);
+        icmp_packet.set_echo_id(1);
+        icmp_packet.set_echo_seq(7);
+        icmp_packet.data_mut().copy_from_slice(b"ping");
+        icmp_packet.fill_checksum();
+        handle.inject(&ping);
+
+        // Neither a frame for another host nor a ping to the subnet broadcast is answered
+        let mut other_host = ping;
+        other_host[..6].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+        handle.inject(&other_host);
+        let mut broadcast = ping;
+        let mut ip_packet = ipv4::Ipv4Packet::new_unchecked(&mut broadcast[eth::HEADER_SIZE..]);
+        ip_packet.set_dst_addr(Ipv4Addr::new(10, 0, 0, 255));
+        ip_packet.fill_checksum();
+        handle.inject(&broadcast);
+
+        handle.close();
+        stack.run().await.unwrap();
+
+        let reply = handle.try_recv().unwrap();
+        let frame = eth::EthernetFrame::new_checked(&reply[..]).unwrap();
+        assert_eq!(frame.dmac(), host_mac);
+        let arp_packet = arp::ArpPacket::new_checked(frame.payload()).unwrap();
+        assert_eq!(arp_packet.opcode(), arp::Opcode::ArpReply as u16);
+        assert_eq!(arp_packet.smac(), stack_mac);
+        assert_eq!(arp_packet.dip(), host_ip);
+
+        let reply = handle.try_recv().unwrap();
+        let frame = eth::EthernetFrame::new_checked(&reply[..]).unwrap();
+        assert_eq!(frame.dmac(), host_mac);
+        let ip_packet = ipv4::Ipv4Packet::new_checked(frame.payload()).unwrap();
+        assert!(ip_packet.verify_checksum());
+        assert_eq!(ip_packet.src_addr(), stack_ip);
+        assert_eq!(ip_packet.dst_addr(), host_ip);
+        let icmp_packet = icmpv4::Icmpv4Packet::new_checked(ip_packet.payload()).unwrap();
+        assert!(icmp_packet.verify_checksum());
+        assert_eq!(icmp_packet.msg_type(), icmpv4::MsgType::EchoReply as u8);
+        assert_eq!(icmp_packet.echo_seq(), 7);
+        assert_eq!(icmp_packet.data(), b"ping");
+        assert!(handle.try_recv().is_none());
+        assert_eq!(stack.stats().snapshot().drops.not_for_us, 1);
+    }
+
+    #[tokio::test]
+    async fn joins_multicast_group() {
+        use crate::*;
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE * 4]));
+        let pool = PacketPool::<4>::new(buf).unwrap();
+        let (device, mut handle) = MemoryDevice::new();
+        let mut stack = NettyStack::with_device(device, &pool);
+        let group = Ipv4Addr::new(239, 1, 2, 3);
+        let subscription = stack.multicast().join(group).unwrap();
+
+        let mut datagram = [0u8; eth::HEADER_SIZE + ipv4::HEADER_SIZE + udp::HEADER_SIZE];
+        let mut frame = eth::EthernetFrame::new_unchecked(&mut datagram[..]);
+        frame.set_dmac(eth::multicast_mac(group));
+        frame.set_ethertype(eth::Ethertype::IPv4 as u16);
+        let ip_payload = frame.payload_mut();
+        ip_payload[0] = 0x45;
+        let mut ip_packet = ipv4::Ipv4Packet::new_unchecked(ip_payload);
+        ip_packet.set_total_len((ipv4::HEADER_SIZE + udp::HEADER_SIZE) as u16);
+        ip_packet.set_protocol(ipv4::ProtocolType::Udp as u8);
+        ip_packet.set_src_addr(Ipv4Addr::new(10, 0, 0, 1));
+        ip_packet.set_dst_addr(group);
+        ip_packet.fill_checksum();
+        handle.inject(&datagram);
+        // One with a bad UDP checksum isn't delivered
+        let mut corrupted = datagram;
+        corrupted[eth::HEADER_SIZE + ipv4::HEADER_SIZE + 6] = 0x12;
+        handle.inject(&corrupted);
+
+        // Keep the stack running until the join report has been repeated
+        let run_time = UNSOLICITED_REPORT_INTERVAL + HOUSEKEEPING_INTERVAL * 2;
+        assert!(tokio::time::timeout(run_time, stack.run()).await.is_err());
+
+        let report = handle.try_recv().unwrap();
+        let frame = eth::EthernetFrame::new_checked(&report[..]).unwrap();
+        assert_eq!(frame.dmac(), eth::multicast_mac(igmp::ALL_V3_ROUTERS));
+        let ip_packet = ipv4::Ipv4Packet::new_checked(frame.payload()).unwrap();
+        assert!(ip_packet.verify_checksum());
+        assert_eq!(ip_packet.time_to_live(), 1);
+        assert_eq!(ip_packet.dst_addr(), igmp::ALL_V3_ROUTERS);
+        let payload = ip_packet.payload();
+        assert_eq!(util::checksum(payload), 0);
+        assert_eq!(payload[0], igmp::MsgType::V3MembershipReport as u8);
+        assert_eq!(payload[8], igmp::RecordType::ChangeToExclude as u8);
+        assert_eq!(payload[12..16], group.octets());
+
+        let repeat = handle.try_recv().unwrap();
+        let frame = eth::EthernetFrame::new_checked(&repeat[..]).unwrap();
+        let repeat_packet = ipv4::Ipv4Packet::new_checked(frame.payload()).unwrap();
+        assert_eq!(repeat_packet.payload(), payload);
+        assert!(handle.try_recv().is_none());
+
+        let delivered = subscription.try_recv().unwrap();
+        assert_eq!(delivered.as_slice(), &datagram[eth::HEADER_SIZE..]);
+        assert!(subscription.try_recv().is_none());
+        assert_eq!(stack.stats().snapshot().drops.bad_checksum, 1);
+    }
+
+    #[tokio::test]
+    async fn rarp_client_and_server() {
+        use crate::*;
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE * 4]));
+        let pool = PacketPool::<4>::new(buf).unwrap();
+        let stack_mac = [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+        let client_mac = [0x02, 0, 0, 0, 0, 0x09];
+        let client_ip = Ipv4Addr::new(10, 0, 0, 9);
+
+        // As a server the stack answers for clients in its table
+        let (device, mut handle) = MemoryDevice::new();
+        let mut server = NettyStack::with_device(device, &pool);
+        server.add_rarp_entry(client_mac, client_ip);
+        let request = arp::Ipv4Data {
+            smac: client_mac,
+            sip: Ipv4Addr::UNSPECIFIED,
+            dmac: client_mac,
+            dip: Ipv4Addr::UNSPECIFIED,
+        };
+        handle.inject(&arp_frame(BROADCAST_MAC, arp::Opcode::RarpRequest, request));
+        handle.close();
+        server.run().await.unwrap();
+        let reply = handle.try_recv().unwrap();
+        let (eth_hdr, payload) = eth::Header::decode(&reply).unwrap();
+        assert_eq!(eth_hdr.ethertype, eth::Ethertype::RARP);
+        assert_eq!(eth_hdr.dmac, client_mac);
+        let rarp_packet = arp::ArpPacket::new_checked(payload).unwrap();
+        assert_eq!(rarp_packet.opcode(), arp::Opcode::RarpReply as u16);
+        assert_eq!(rarp_packet.dmac(), client_mac);
+        assert_eq!(rarp_packet.dip(), client_ip);
+
+        // As a client it learns its own address
+        let (device, handle) = MemoryDevice::new();
+        let mut client = NettyStack::with_device(device, &pool);
+        let reply = arp::Ipv4Data {
+            smac: client_mac,
+            sip: client_ip,
+            dmac: stack_mac,
+            dip: Ipv4Addr::new(10, 0, 0, 20),
+        };
+        handle.inject(&arp_frame(stack_mac, arp::Opcode::RarpReply, reply));
+        let learnt = client.learn_ipaddr(Duration::from_secs(1)).await.unwrap();
+        assert_eq!(learnt, Ipv4Addr::new(10, 0, 0, 20));
+
+        // With no answer it keeps the address it had
+        let timeout = Duration::from_millis(50);
+        assert!(client.learn_ipaddr(timeout).await.is_err());
+        assert_eq!(client.ipaddr(), learnt);
+    }
+
+    #[tokio::test]
+    async fn proxies_arp_for_configured_prefixes() {
+        use crate::*;
+        let host_mac = [0x02, 0, 0, 0, 0, 0x01];
+        let host_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let arp_request = |dip| {
+            let data = arp::Ipv4Data {
+                smac: host_mac,
+                sip: host_ip,
+                dmac: [0; 6],
+                dip,
+            };
+            arp_frame(BROADCAST_MAC, arp::Opcode::ArpRequest, data)
+        };
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE * 4]));
+        let pool = PacketPool::<4>::new(buf).unwrap();
+        let (device, mut handle) = MemoryDevice::new();
+        let mut stack = NettyStack::with_device(device, &pool);
+        stack.add_proxy_arp(ipv4::Ipv4Cidr::new(Ipv4Addr::new(192, 168, 1, 0), 24));
+
+        handle.inject(&arp_request(Ipv4Addr::new(10, 0, 0, 77)));
+        handle.inject(&arp_request(Ipv4Addr::new(192, 168, 1, 7)));
+        handle.close();
+        stack.run().await.unwrap();
+
+        let reply = handle.try_recv().unwrap();
+        let (eth_hdr, payload) = eth::Header::decode(&reply).unwrap();
+        assert_eq!(eth_hdr.dmac, host_mac);
+        let arp_packet = arp::ArpPacket::new_checked(payload).unwrap();
+        assert_eq!(arp_packet.opcode(), arp::Opcode::ArpReply as u16);
+        assert_eq!(arp_packet.smac(), [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+        assert_eq!(arp_packet.sip(), Ipv4Addr::new(192, 168, 1, 7));
+        assert!(handle.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn resists_arp_spoofing() {
+        use crate::*;
+        let broadcast_arp = |opcode, smac, sip, dip| {
+            let data = arp::Ipv4Data {
+                smac,
+                sip,
+                dmac: [0; 6],
+                dip,
+            };
+            arp_frame(BROADCAST_MAC, opcode, data)
+        };
+        let host_mac = [0x02, 0, 0, 0, 0, 0x01];
+        let host_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let attacker_mac = [0x02, 0, 0, 0, 0, 0x66];
+        let our_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE * 4]));
+        let pool = PacketPool::<4>::new(buf).unwrap();
+        let (device, mut handle) = MemoryDevice::new();
+        let mut stack = NettyStack::with_device(device, &pool);
+        stack.set_arp_policy(ArpPolicy {
+            accept_unsolicited_replies: false,
+            learn_overheard: false,
+            lock_time: Duration::from_secs(1),
+            rate_limit: Some(3),
+        });
+        let arp_table = stack.arp_table();
+
+        handle.inject(&broadcast_arp(
+            arp::Opcode::ArpRequest,
+            host_mac,
+            host_ip,
+            our_ip,
+        ));
+        // An unsolicited reply doesn't add an entry, and a claim on a freshly confirmed
+        // one is refused
+        let unknown_ip = Ipv4Addr::new(10, 0, 0, 9);
+        handle.inject(&broadcast_arp(
+            arp::Opcode::ArpReply,
+            attacker_mac,
+            unknown_ip,
+            our_ip,
+        ));
+        for _ in 0..3 {
+            handle.inject(&broadcast_arp(
+                arp::Opcode::ArpReply,
+                attacker_mac,
+                host_ip,
+                our_ip,
+            ));
+        }
+        handle.close();
+        stack.run().await.unwrap();
+
+        assert_eq!(arp_table.lookup(host_ip), Some(host_mac));
+        assert_eq!(arp_table.lookup(unknown_ip), None);
+        let stats = stack.stats().snapshot();
+        assert_eq!(stats.arp_conflicts, 2);
+        assert_eq!(stats.drops.rate_limited, 1);
+        assert!(handle.try_recv().is_some());
+        assert!(handle.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn filters_frames() {
+        use crate::filter::{Action, Chain, Rule};
+        use crate::*;
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE * 4]));
+        let pool = PacketPool::<4>::new(buf).unwrap();
+        let (device, mut handle) = MemoryDevice::new();
+        let mut stack = NettyStack::with_device(device, &pool);
+        let host_mac = [0x02, 0, 0, 0, 0, 0x01];
+        let host_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let stack_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let filter = stack.filter();
+        filter.append(Chain::Ingress, Rule::new(Action::Count).smac(host_mac));
+        let lan = ipv4::Ipv4Cidr::new(host_ip, 24);
+        filter.append(
+            Chain::Input,
+            Rule::new(Action::Reject)
+                .src(lan)
+                .icmp_type(icmpv4::MsgType::EchoRequest),
+        );
+        filter.append(
+            Chain::Output,
+            Rule::new(Action::Drop).ethertype(eth::Ethertype::ARP),
+        );
+
+        let request = arp::Ipv4Data {
+            smac: host_mac,
+            sip: host_ip,
+            dmac: [0; 6],
+            dip: stack_ip,
+        };
+        handle.inject(&arp_frame(BROADCAST_MAC, arp::Opcode::ArpRequest, request));
+
+        let ping = PacketBuilder::ethernet(host_mac, [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff])
+            .ipv4(host_ip, stack_ip)
+            .icmpv4(Icmpv4Message::echo_request(1, 7));
+        let mut packet = pool.allocate().unwrap();
+        packet.reserve(DEFAULT_HEADROOM).unwrap();
+        packet.put(4).unwrap().copy_from_slice(b"ping");
+        ping.finalize(&mut packet).unwrap();
+        handle.inject(packet.as_slice());
+        packet.discard();
+        handle.close();
+        stack.run().await.unwrap();
+
+        // The ARP reply is dropped on the way out, and the ping is rejected
+        let reply = handle.try_recv().unwrap();
+        let frame = eth::EthernetFrame::new_checked(&reply[..]).unwrap();
+        assert_eq!(frame.dmac(), host_mac);
+        let ip_packet = ipv4::Ipv4Packet::new_checked(frame.payload()).unwrap();
+        assert_eq!(ip_packet.dst_addr(), host_ip);
+        let icmp_packet = icmpv4::Icmpv4Packet::new_checked(ip_packet.payload()).unwrap();
+        assert!(icmp_packet.verify_checksum());
+        assert_eq!(
+            icmp_packet.msg_type(),
+            icmpv4::MsgType::DestinationUnreachable as u8
+        );
+        assert_eq!(icmp_packet.code(), icmpv4::CODE_ADMIN_PROHIBITED);
+        // The quoted header is the ping's
+        let quoted = &ip_packet.payload()[icmpv4::HEADER_SIZE + 4..];
+        assert_eq!(quoted.len(), ipv4::HEADER_SIZE + 8);
+        assert_eq!(ipv4::Ipv4Packet::new_unchecked(quoted).dst_addr(), stack_ip);
+        assert!(handle.try_recv().is_none());
+
+        assert_eq!(stack.stats().snapshot().drops.filtered, 2);
+        let counted = filter.rules(Chain::Ingress);
+        assert_eq!(counted[0].packets, 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_only_frames_for_us() {
+        use crate::filter::{Action, Chain, Rule};
+        use crate::*;
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE * 4]));
+        let pool = PacketPool::<4>::new(buf).unwrap();
+        let (device, mut handle) = MemoryDevice::new();
+        let mut stack = NettyStack::with_device(device, &pool);
+        let stack_mac = [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+        let other_mac = [0x02, 0, 0, 0, 0, 0x99];
+        let host_mac = [0x02, 0, 0, 0, 0, 0x01];
+        let host_ip = Ipv4Addr::new(10, 0, 0, 1);
+        stack.set_promiscuous(true);
+        stack.filter().append(
+            Chain::Ingress,
+            Rule::new(Action::Reject).icmp_type(icmpv4::MsgType::EchoRequest),
+        );
+
+        // A ping for another host is dropped quietly, one for us is refused
+        for dmac in [other_mac, stack_mac] {
+            let mut packet = pool.allocate().unwrap();
+            packet.reserve(DEFAULT_HEADROOM).unwrap();
+            packet.put(4).unwrap().copy_from_slice(b"ping");
+            PacketBuilder::ethernet(host_mac, dmac)
+                .ipv4(host_ip, stack.ipaddr())
+                .icmpv4(Icmpv4Message::echo_request(1, 7))
+                .finalize(&mut packet)
+                .unwrap();
+            handle.inject(packet.as_slice());
+            packet.discard();
+        }
+        handle.close();
+        stack.run().await.unwrap();
+
+        let reply = handle.try_recv().unwrap();
+        let frame = eth::EthernetFrame::new_checked(&reply[..]).unwrap();
+        let ip_packet = ipv4::Ipv4Packet::new_checked(frame.payload()).unwrap();
+        let icmp_packet = icmpv4::Icmpv4Packet::new_checked(ip_packet.payload()).unwrap();
+        assert_eq!(icmp_packet.code(), icmpv4::CODE_ADMIN_PROHIBITED);
+        assert!(handle.try_recv().is_none());
+        assert_eq!(stack.stats().snapshot().drops.filtered, 2);
+    }
+
+    #[tokio::test]
+    async fn filters_by_connection_state() {
+        use crate::conntrack::ConnState;
+        use crate::filter::{Action, Chain, Rule};
+        use crate::*;
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE * 4]));
+        let pool = PacketPool::<4>::new(buf).unwrap();
+        let (device, mut handle) = MemoryDevice::new();
+        let mut stack = NettyStack::with_device(device, &pool);
+        let stack_mac = [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+        let stack_ip = stack.ipaddr();
+        let host_mac = [0x02, 0, 0, 0, 0, 0x01];
+        let host_ip = Ipv4Addr::new(10, 0, 0, 1);
+        stack.arp_table().insert_static(host_ip, host_mac);
+        let filter = stack.filter();
+        filter.append(
+            Chain::Input,
+            Rule::new(Action::Accept).state(ConnState::Established),
+        );
+        filter.append(Chain::Input, Rule::new(Action::Drop).state(ConnState::New));
+
+        // The stack asks the host something, so the answer is let in
+        let mut packet = pool.allocate().unwrap();
+        packet.reserve(DEFAULT_HEADROOM).unwrap();
+        packet.put(5).unwrap().copy_from_slice(b"query");
+        PacketBuilder::ipv4(stack_ip, host_ip)
+            .udp(5000, 53)
+            .finalize(&mut packet)
+            .unwrap();
+        packet.send();
+        let run = tokio::time::timeout(Duration::from_millis(100), stack.run());
+        assert!(run.await.is_err());
+        assert!(handle.try_recv().is_some());
+
+        // Nobody asked for the other datagrams, and they don't start flows
+        for src_port in [53, 54, 55] {
+            let mut packet = pool.allocate().unwrap();
+            packet.reserve(DEFAULT_HEADROOM).unwrap();
+            packet.put(6).unwrap().copy_from_slice(b"answer");
+            PacketBuilder::ethernet(host_mac, stack_mac)
+                .ipv4(host_ip, stack_ip)
+                .udp(src_port, 5000)
+                .finalize(&mut packet)
+                .unwrap();
+            handle.inject(packet.as_slice());
+            packet.discard();
+        }
+        handle.close();
+        stack.run().await.unwrap();
+
+        let rules = filter.rules(Chain::Input);
+        assert_eq!((rules[0].packets, rules[1].packets), (1, 2));
+        assert_eq!(stack.stats().snapshot().drops.filtered, 2);
+        let connections = stack.conntrack().connections();
+        assert_eq!(connections.len(), 1);
+        assert!(connections[0].established);
+    }
+
+    #[tokio::test]
+    async fn masquerades_inside_hosts() {
+        use crate::*;
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE * 4]));
+        let pool = PacketPool::<4>::new(buf).unwrap();
+        let (device, mut handle) = MemoryDevice::new();
+        let stack_mac = [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+        let stack_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let inside_mac = [0x02, 0, 0, 0, 0, 0x10];
+        let inside_ip = Ipv4Addr::new(192, 168, 1, 10);
+        let server_mac = [0x02, 0, 0, 0, 0, 0x01];
+        let server_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let mut stack = NettyStack::with_device(device, &pool);
+        stack.nat().add_inside(ipv4::Ipv4Cidr::new(inside_ip, 24));
+        stack.arp_table().insert_static(inside_ip, inside_mac);
+        stack.arp_table().insert_static(server_ip, server_mac);
+
+        let ping = |smac, src, dst, time_to_live, message| {
+            let mut packet = pool.allocate().unwrap();
+            packet.reserve(DEFAULT_HEADROOM).unwrap();
+            packet.put(4).unwrap().copy_from_slice(b"ping");
+            PacketBuilder::ethernet(smac, stack_mac)
+                .ipv4(src, dst)
+                .time_to_live(time_to_live)
+                .icmpv4(message)
+                .finalize(&mut packet)
+                .unwrap();
+            let frame = packet.as_slice().to_vec();
+            packet.discard();
+            frame
+        };
+        handle.inject(&ping(
+            inside_mac,
+            inside_ip,
+            server_ip,
+            64,
+            Icmpv4Message::echo_request(7, 1),
+        ));
+        let outside_id = nat::NAT_PORTS_START;
+        handle.inject(&ping(
+            server_mac,
+            server_ip,
+            stack_ip,
+            64,
+            Icmpv4Message::echo_reply(outside_id, 1),
+        ));
+        // The last hop it had left was to the stack
+        handle.inject(&ping(
+            inside_mac,
+            inside_ip,
+            server_ip,
+            1,
+            Icmpv4Message::echo_request(8, 1),
+        ));
+        handle.close();
+        stack.run().await.unwrap();
+
+        for (dmac, src, dst, id) in [
+            (server_mac, stack_ip, server_ip, outside_id),
+            (inside_mac, server_ip, inside_ip, 7),
+        ] {
+            let frame = handle.try_recv().unwrap();
+            let frame = eth::EthernetFrame::new_checked(&frame[..]).unwrap();
+            assert_eq!(frame.dmac(), dmac);
+            let ip_packet = ipv4::Ipv4Packet::new_checked(frame.payload()).unwrap();
+            assert!(ip_packet.verify_checksum());
+            assert_eq!(ip_packet.time_to_live(), 63);
+            assert_eq!((ip_packet.src_addr(), ip_packet.dst_addr()), (src, dst));
+            let icmp_packet = icmpv4::Icmpv4Packet::new_checked(ip_packet.payload()).unwrap();
+            assert!(icmp_packet.verify_checksum());
+            assert_eq!(icmp_packet.echo_id(), id);
+        }
+
+        let frame = handle.try_recv().unwrap();
+        let frame = eth::EthernetFrame::new_checked(&frame[..]).unwrap();
+        assert_eq!(frame.dmac(), inside_mac);
+        let ip_packet = ipv4::Ipv4Packet::new_checked(frame.payload()).unwrap();
+        assert_eq!(
+            (ip_packet.src_addr(), ip_packet.dst_addr()),
+            (stack_ip, inside_ip)
+        );
+        let icmp_packet = icmpv4::Icmpv4Packet::new_checked(ip_packet.payload()).unwrap();
+        assert_eq!(icmp_packet.msg_type(), icmpv4::MsgType::TimeExceeded as u8);
+        let quoted = ipv4::Ipv4Packet::new_unchecked(&ip_packet.payload()[8..]);
+        assert_eq!(quoted.src_addr(), inside_ip);
+        assert_eq!(stack.stats().snapshot().drops.ttl_expired, 1);
+        // The expired request didn't get a mapping
+        let mappings = stack.nat().mappings();
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].inside_port, 7);
+        assert!(handle.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn forwards_echo_to_inside_host() {
+        use crate::*;
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE * 4]));
+        let pool = PacketPool::<4>::new(buf).unwrap();
+        let (device, mut handle) = MemoryDevice::new();
+        let stack_mac = [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+        let stack_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let client_mac = [0x02, 0, 0, 0, 0, 0x01];
+        let client_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let server_mac = [0x02, 0, 0, 0, 0, 0x20];
+        let server_ip = Ipv4Addr::new(192, 168, 1, 20);
+        let mut stack = NettyStack::with_device(device, &pool);
+        assert!(stack.nat().add_port_forward(nat::PortForward {
+            protocol: ipv4::ProtocolType::IcmpV4,
+            outside_port: 5000,
+            inside_addr: server_ip,
+            inside_port: 1,
+        }));
+        stack.arp_table().insert_static(client_ip, client_mac);
+        stack.arp_table().insert_static(server_ip, server_mac);
+
+        let echo = |smac, src, dst, message| {
+            let datagram =
+                nat::test_datagram(PacketBuilder::ipv4(src, dst).icmpv4(message), b"ping");
+            let mut frame = vec![0u8; eth::HEADER_SIZE];
+            let mut eth_frame = eth::EthernetFrame::new_unchecked(&mut frame[..]);
+            eth_frame.set_dmac(stack_mac);
+            eth_frame.set_smac(smac);
+            eth_frame.set_ethertype(eth::Ethertype::IPv4 as u16);
+            frame.extend_from_slice(&datagram);
+            frame
+        };
+        handle.inject(&echo(
+            client_mac,
+            client_ip,
+            stack_ip,
+            Icmpv4Message::echo_request(5000, 1),
+        ));
+        handle.inject(&echo(
+            server_mac,
+            server_ip,
+            client_ip,
+            Icmpv4Message::echo_reply(1, 1),
+        ));
+        handle.close();
+        stack.run().await.unwrap();
+
+        for (dmac, src, dst, id) in [
+            (server_mac, client_ip, server_ip, 1),
+            (client_mac, stack_ip, client_ip, 5000),
+        ] {
+            let frame = handle.try_recv().unwrap();
+            let frame = eth::EthernetFrame::new_checked(&frame[..]).unwrap();
+            assert_eq!(frame.dmac(), dmac);
+            let ip_packet = ipv4::Ipv4Packet::new_checked(frame.payload()).unwrap();
+            assert!(ip_packet.verify_checksum());
+            assert_eq!((ip_packet.src_addr(), ip_packet.dst_addr()), (src, dst));
+            let icmp_packet = icmpv4::Icmpv4Packet::new_checked(ip_packet.payload()).unwrap();
+            assert!(icmp_packet.verify_checksum());
+            assert_eq!(icmp_packet.echo_id(), id);
+        }
+        assert!(handle.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn counts_shaped_frames_when_written() {
+        use crate::*;
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE * 4]));
+        let pool = PacketPool::<4>::new(buf).unwrap();
+        let (device, mut handle) = MemoryDevice::new();
+        let mut stack = NettyStack::with_device(device, &pool);
+        let stack_mac = [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+        let host_mac = [0x02, 0, 0, 0, 0, 0x01];
+        let host_ip = Ipv4Addr::new(10, 0, 0, 1);
+        stack.arp_table().insert_static(host_ip, host_mac);
+        // One full frame goes out at once, one more waits and the last is dropped
+        stack.set_egress_shaping(Some(ShaperConfig {
+            rate: 1,
+            burst: 0,
+            queue_len: 1,
+        }));
+        for seq in 0..3 {
+            let mut packet = pool.allocate().unwrap();
+            packet.reserve(DEFAULT_HEADROOM).unwrap();
+            packet.put(1000).unwrap().fill(0);
+            PacketBuilder::ethernet(host_mac, stack_mac)
+                .ipv4(host_ip, stack.ipaddr())
+                .icmpv4(Icmpv4Message::echo_request(1, seq))
+                .finalize(&mut packet)
+                .unwrap();
+            handle.inject(packet.as_slice());
+            packet.discard();
+        }
+        handle.close();
+        stack.run().await.unwrap();
+
+        assert!(handle.try_recv().is_some());
+        assert!(handle.try_recv().is_none());
+        let snapshot = stack.stats().snapshot();
+        assert_eq!(
+            snapshot.icmp_out_by_type[icmpv4::MsgType::EchoReply as usize],
+            1
+        );
+        assert_eq!(snapshot.drops.queue_full, 1);
+        assert_eq!(stack.egress_queued()[shaper::PRIORITY_QUEUES - 1], 1);
+    }
+
+    #[tokio::test]
+    async fn sends_broadcast_and_multicast_without_arp() {
+        use crate::*;
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE * 4]));
+        let pool = PacketPool::<4>::new(buf).unwrap();
+        let (device, mut handle) = MemoryDevice::new();
+        let mut stack = NettyStack::with_device(device, &pool);
+        let group = Ipv4Addr::new(239, 1, 2, 3);
+        let subnet_broadcast = Ipv4Addr::new(10, 0, 0, 255);
+        for dst_addr in [Ipv4Addr::BROADCAST, subnet_broadcast, group] {
+            let mut packet = pool.allocate().unwrap();
+            packet.reserve(DEFAULT_HEADROOM).unwrap();
+            packet.put(4).unwrap().copy_from_slice(b"ping");
+            PacketBuilder::ipv4(stack.ipaddr(), dst_addr)
+                .udp(5000, 5000)
+                .finalize(&mut packet)
+                .unwrap();
+            packet.send();
+        }
+
+        let run = tokio::time::timeout(Duration::from_millis(100), stack.run());
+        assert!(run.await.is_err());
+        let mut dmacs = Vec::new();
+        while let Some(frame) = handle.try_recv() {
+            let frame = eth::EthernetFrame::new_checked(&frame[..]).unwrap();
+            assert_eq!(frame.ethertype(), eth::Ethertype::IPv4 as u16);
+            dmacs.push(frame.dmac());
+        }
+        dmacs.sort();
+        assert_eq!(
+            dmacs,
+            [eth::multicast_mac(group), BROADCAST_MAC, BROADCAST_MAC]
+        );
+        assert_eq!(stack.stats().snapshot().drops.arp_miss, 0);
+    }
 }
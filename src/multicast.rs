@@ -0,0 +1,172 @@
+use crate::error::{NettyError, Result};
+use crate::eth;
+use crate::packet_pool::{Packet, PacketPool, PacketStatus};
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// How many datagrams can wait for a subscriber to read them before new ones are dropped
+const SUBSCRIPTION_QUEUE_LEN: usize = 8;
+
+struct Subscriber<'pool, const PKT_POOL_SZ: usize> {
+    id: u64,
+    group: Ipv4Addr,
+    tx: async_channel::Sender<Packet<'pool, 'pool, PKT_POOL_SZ>>,
+}
+
+/// The multicast groups the application has joined. It's shared with the application
+/// through an `Arc` so groups can be joined and left while the stack is running; the
+/// stack sends the IGMP reports for each change.
+pub struct MulticastGroups<'pool, const PKT_POOL_SZ: usize> {
+    subscribers: Mutex<Vec<Subscriber<'pool, PKT_POOL_SZ>>>,
+    next_id: AtomicU64,
+    changed: Notify,
+}
+
+impl<'pool, const PKT_POOL_SZ: usize> Default for MulticastGroups<'pool, PKT_POOL_SZ> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'pool, const PKT_POOL_SZ: usize> MulticastGroups<'pool, PKT_POOL_SZ> {
+    pub fn new() -> Self {
+        Self {
+            subscribers: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(0),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Joins `group` and returns a subscription which receives every datagram sent to it.
+    /// The group is left once every subscription to it has been dropped.
+    pub fn join(
+        self: &Arc<Self>,
+        group: Ipv4Addr,
+    ) -> io::Result<MulticastSubscription<'pool, PKT_POOL_SZ>> {
+        if !group.is_multicast() {
+            log::error!("{} is not a multicast address", group);
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let (tx, rx) = async_channel::bounded(SUBSCRIPTION_QUEUE_LEN);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push(Subscriber { id, group, tx });
+        self.changed.notify_one();
+        Ok(MulticastSubscription {
+            id,
+            group,
+            rx,
+            groups: self.clone(),
+        })
+    }
+
+    /// The groups with at least one subscription, in ascending order
+    pub fn groups(&self) -> Vec<Ipv4Addr> {
+        let mut groups: Vec<_> = self
+            .subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|subscriber| subscriber.group)
+            .collect();
+        groups.sort();
+        groups.dedup();
+        groups
+    }
+
+    pub fn is_member(&self, group: Ipv4Addr) -> bool {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|subscriber| subscriber.group == group)
+    }
+
+    /// Whether `dmac` is the MAC address of a group with a subscription. Several groups
+    /// share each MAC address.
+    pub fn accepts_mac(&self, dmac: [u8; 6]) -> bool {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|subscriber| eth::multicast_mac(subscriber.group) == dmac)
+    }
+
+    fn leave(&self, id: u64) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.id != id);
+        self.changed.notify_one();
+    }
+
+    /// Waits until a group may have been joined or left
+    pub(crate) async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    /// Copies `datagram` into a new packet for every subscriber to `group`. Subscribers
+    /// which aren't keeping up miss the datagram. Returns how many got a copy.
+    pub(crate) fn deliver(
+        &self,
+        group: Ipv4Addr,
+        datagram: &[u8],
+        pkt_pool: &'pool PacketPool<'pool, PKT_POOL_SZ>,
+    ) -> Result<usize> {
+        let subscribers = self.subscribers.lock().unwrap();
+        let mut delivered = 0;
+        for subscriber in subscribers.iter().filter(|sub| sub.group == group) {
+            let mut packet = pkt_pool.allocate().ok_or(NettyError::PoolExhausted)?;
+            if let Err(err) = packet.put(datagram.len()) {
+                packet.discard();
+                return Err(err.into());
+            }
+            packet.as_mut_slice().copy_from_slice(datagram);
+            packet.set_status(PacketStatus::ReadyToRead);
+            match subscriber.tx.try_send(packet) {
+                Ok(()) => delivered += 1,
+                Err(err) => {
+                    log::warn!("Subscriber to {} is full, dropping datagram", group);
+                    err.into_inner().discard();
+                }
+            }
+        }
+        Ok(delivered)
+    }
+}
+
+/// Datagrams sent to a multicast group the application joined. Each one is an IPv4
+/// datagram starting at its header.
+pub struct MulticastSubscription<'pool, const PKT_POOL_SZ: usize> {
+    id: u64,
+    group: Ipv4Addr,
+    rx: async_channel::Receiver<Packet<'pool, 'pool, PKT_POOL_SZ>>,
+    groups: Arc<MulticastGroups<'pool, PKT_POOL_SZ>>,
+}
+
+impl<'pool, const PKT_POOL_SZ: usize> MulticastSubscription<'pool, PKT_POOL_SZ> {
+    pub fn group(&self) -> Ipv4Addr {
+        self.group
+    }
+
+    /// Waits for the next datagram sent to the group
+    pub async fn recv(&self) -> Packet<'pool, 'pool, PKT_POOL_SZ> {
+        // The sender lives as long as the subscription, so the channel never closes
+        self.rx.recv().await.unwrap()
+    }
+
+    pub fn try_recv(&self) -> Option<Packet<'pool, 'pool, PKT_POOL_SZ>> {
+        self.rx.try_recv().ok()
+    }
+}
+
+impl<'pool, const PKT_POOL_SZ: usize> Drop for MulticastSubscription<'pool, PKT_POOL_SZ> {
+    fn drop(&mut self) {
+        self.groups.leave(self.id);
+    }
+}
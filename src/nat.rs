@@ -0,0 +1,772 @@
+use crate::conntrack::{self, FlowKey};
+use crate::ipv4::{self, Ipv4Cidr};
+use crate::{icmpv4, udp, util};
+use byteorder::{ByteOrder, NetworkEndian};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The most mappings kept at once. Inside hosts can't start new flows while it's full.
+pub const NAT_ENTRIES: usize = 256;
+/// The first outside port, or ICMP echo identifier, given to a mapping
+pub const NAT_PORTS_START: u16 = 49152;
+/// The last outside port, or ICMP echo identifier, given to a mapping
+pub const NAT_PORTS_END: u16 = 65535;
+
+/// A translation as seen by `NatTable::mappings`. For ICMP echo flows the ports are the
+/// echo identifiers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NatMapping {
+    pub protocol: u8,
+    pub inside_addr: Ipv4Addr,
+    pub inside_port: u16,
+    pub outside_port: u16,
+    /// The outside host and port a port forward was used by. Masqueraded flows have none.
+    pub remote: Option<(Ipv4Addr, u16)>,
+    /// Time until the mapping is forgotten unless more of its datagrams are seen
+    pub expires_in: Duration,
+}
+
+/// Sends datagrams for `outside_port` on the stack's address to `inside_port` on an
+/// inside host. For ICMP the ports are echo identifiers: echo requests with the outside
+/// identifier reach the inside host with the inside one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PortForward {
+    pub protocol: ipv4::ProtocolType,
+    pub outside_port: u16,
+    pub inside_addr: Ipv4Addr,
+    pub inside_port: u16,
+}
+
+impl PortForward {
+    fn forwards(&self, protocol: u8, outside_port: u16) -> bool {
+        self.protocol as u8 == protocol && self.outside_port == outside_port
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Mapping {
+    protocol: u8,
+    inside_addr: Ipv4Addr,
+    inside_port: u16,
+    outside_port: u16,
+    remote: Option<(Ipv4Addr, u16)>,
+    /// Whether a datagram has come back through the mapping
+    answered: bool,
+    expires_at: Instant,
+}
+
+impl Mapping {
+    fn refresh(&mut self, now: Instant) {
+        let timeout = if self.protocol == ipv4::ProtocolType::IcmpV4 as u8 {
+            conntrack::ICMP_TIMEOUT
+        } else if self.answered {
+            conntrack::UDP_STREAM_TIMEOUT
+        } else {
+            conntrack::UDP_TIMEOUT
+        };
+        self.expires_at = now + timeout;
+    }
+}
+
+#[derive(Default)]
+struct NatState {
+    inside: Vec<Ipv4Cidr>,
+    port_forwards: Vec<PortForward>,
+    mappings: Vec<Mapping>,
+    next_port: u16,
+}
+
+/// Network address translation for hosts which use the stack as their gateway. Hosts on
+/// inside networks are masqueraded: their datagrams leave with the stack's own address as
+/// the source, and a port or echo identifier which tells the answers apart. Port
+/// forwards let outside hosts reach services on inside hosts through the stack's address.
+/// It's shared with the application through an `Arc` so it can be configured and its
+/// mappings inspected while the stack is running.
+pub struct NatTable {
+    state: Mutex<NatState>,
+}
+
+impl Default for NatTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NatTable {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(NatState {
+                next_port: NAT_PORTS_START,
+                ..NatState::default()
+            }),
+        }
+    }
+
+    /// Masquerades datagrams from `prefix` as coming from the stack's address
+    pub fn add_inside(&self, prefix: Ipv4Cidr) {
+        let mut state = self.state.lock().unwrap();
+        if !state.inside.contains(&prefix) {
+            state.inside.push(prefix);
+        }
+    }
+
+    pub fn remove_inside(&self, prefix: Ipv4Cidr) {
+        self.state.lock().unwrap().inside.retain(|&p| p != prefix);
+    }
+
+    pub fn inside(&self) -> Vec<Ipv4Cidr> {
+        self.state.lock().unwrap().inside.clone()
+    }
+
+    /// Adds a port forward. Returns false if the protocol and outside port are already
+    /// forwarded.
+    pub fn add_port_forward(&self, forward: PortForward) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let taken = state
+            .port_forwards
+            .iter()
+            .any(|existing| existing.forwards(forward.protocol as u8, forward.outside_port));
+        if taken {
+            return false;
+        }
+        state.port_forwards.push(forward);
+        true
+    }
+
+    /// Removes a port forward, and the mappings of the flows which came through it
+    pub fn remove_port_forward(
+        &self,
+        protocol: ipv4::ProtocolType,
+        outside_port: u16,
+    ) -> Option<PortForward> {
+        let mut state = self.state.lock().unwrap();
+        let position = state
+            .port_forwards
+            .iter()
+            .position(|forward| forward.forwards(protocol as u8, outside_port))?;
+        let removed = state.port_forwards.remove(position);
+        state.mappings.retain(|mapping| {
+            mapping.remote.is_none() || !removed.forwards(mapping.protocol, mapping.outside_port)
+        });
+        Some(removed)
+    }
+
+    pub fn port_forwards(&self) -> Vec<PortForward> {
+        self.state.lock().unwrap().port_forwards.clone()
+    }
+
+    /// Every mapping, oldest first
+    pub fn mappings(&self) -> Vec<NatMapping> {
+        let now = Instant::now();
+        self.state
+            .lock()
+            .unwrap()
+            .mappings
+            .iter()
+            .map(|mapping| NatMapping {
+                protocol: mapping.protocol,
+                inside_addr: mapping.inside_addr,
+                inside_port: mapping.inside_port,
+                outside_port: mapping.outside_port,
+                remote: mapping.remote,
+                expires_in: mapping.expires_at.saturating_duration_since(now),
+            })
+            .collect()
+    }
+
+    /// Forgets every mapping. Answers to datagrams already sent will be dropped.
+    pub fn flush(&self) {
+        self.state.lock().unwrap().mappings.clear();
+    }
+
+    /// Rewrites a datagram from an inside host to an outside one so it comes from
+    /// `outside_addr`: either it answers a port forward, or it's from an inside network.
+    /// Returns false if the datagram isn't one to translate.
+    pub(crate) fn translate_outbound(&self, datagram: &mut [u8], outside_addr: Ipv4Addr) -> bool {
+        match self.outbound(datagram, outside_addr, true) {
+            Some(translation) => {
+                translation.apply(datagram);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether `translate_outbound` would translate a datagram, without adding or
+    /// refreshing a mapping
+    pub(crate) fn translates_outbound(&self, datagram: &[u8], outside_addr: Ipv4Addr) -> bool {
+        self.outbound(datagram, outside_addr, false).is_some()
+    }
+
+    /// Rewrites a datagram sent to `outside_addr` so it goes to an inside host: either it
+    /// answers a masqueraded flow, or a port forward matches it. ICMP errors about
+    /// datagrams which left through a mapping go back to the inside host too. Returns
+    /// false if there's no mapping or port forward for it.
+    pub(crate) fn translate_inbound(&self, datagram: &mut [u8], outside_addr: Ipv4Addr) -> bool {
+        match self.inbound(datagram, outside_addr, true) {
+            Some(translation) => {
+                translation.apply(datagram);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether `translate_inbound` would translate a datagram, without adding or
+    /// refreshing a mapping
+    pub(crate) fn translates_inbound(&self, datagram: &[u8], outside_addr: Ipv4Addr) -> bool {
+        self.inbound(datagram, outside_addr, false).is_some()
+    }
+
+    /// Finds how to translate a datagram leaving the inside. Mappings are only added or
+    /// refreshed if `record` is set.
+    fn outbound(
+        &self,
+        datagram: &[u8],
+        outside_addr: Ipv4Addr,
+        record: bool,
+    ) -> Option<Translation> {
+        let (key, kind) = translatable(datagram)?;
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if kind != Kind::Request {
+            // An ICMP echo reply carries the inside identifier, not the remote host's
+            let icmp = key.protocol == ipv4::ProtocolType::IcmpV4 as u8;
+            let forwarded = state.mappings.iter_mut().find(|mapping| {
+                mapping.protocol == key.protocol
+                    && mapping.inside_addr == key.src_addr
+                    && mapping.inside_port == key.src_port
+                    && mapping.remote.is_some_and(|(addr, port)| {
+                        addr == key.dst_addr && (icmp || port == key.dst_port)
+                    })
+            });
+            if let Some(mapping) = forwarded {
+                if record {
+                    mapping.answered = true;
+                    mapping.refresh(now);
+                }
+                return Some(Translation::Datagram(
+                    Side::Src,
+                    outside_addr,
+                    mapping.outside_port,
+                ));
+            }
+        }
+
+        let inside = |addr| state.inside.iter().any(|prefix| prefix.contains(addr));
+        if kind == Kind::Answer || !inside(key.src_addr) || inside(key.dst_addr) {
+            return None;
+        }
+        let existing = state.mappings.iter_mut().find(|mapping| {
+            mapping.protocol == key.protocol
+                && mapping.inside_addr == key.src_addr
+                && mapping.inside_port == key.src_port
+                && mapping.remote.is_none()
+        });
+        let outside_port = match existing {
+            Some(mapping) => {
+                if record {
+                    mapping.refresh(now);
+                }
+                mapping.outside_port
+            }
+            None if record => state.add_mapping(key, now)?,
+            None => state.free_port(key.protocol)?,
+        };
+        Some(Translation::Datagram(Side::Src, outside_addr, outside_port))
+    }
+
+    /// Finds how to translate a datagram sent to `outside_addr`. Mappings are only added
+    /// or refreshed if `record` is set.
+    fn inbound(
+        &self,
+        datagram: &[u8],
+        outside_addr: Ipv4Addr,
+        record: bool,
+    ) -> Option<Translation> {
+        let (key, kind) = match translatable(datagram) {
+            Some((key, kind)) if key.dst_addr == outside_addr => (key, kind),
+            Some(_) => return None,
+            None => return self.icmp_error(datagram, outside_addr),
+        };
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if kind != Kind::Request {
+            let masqueraded = state.mappings.iter_mut().find(|mapping| {
+                mapping.protocol == key.protocol
+                    && mapping.outside_port == key.dst_port
+                    && mapping.remote.is_none()
+            });
+            if let Some(mapping) = masqueraded {
+                if record {
+                    mapping.answered = true;
+                    mapping.refresh(now);
+                }
+                return Some(Translation::Datagram(
+                    Side::Dst,
+                    mapping.inside_addr,
+                    mapping.inside_port,
+                ));
+            }
+        }
+
+        if kind == Kind::Answer {
+            return None;
+        }
+        let forward = *state
+            .port_forwards
+            .iter()
+            .find(|forward| forward.forwards(key.protocol, key.dst_port))?;
+        let (outside_port, inside_port) = (forward.outside_port, forward.inside_port);
+        let remote = Some((key.src_addr, key.src_port));
+        let existing = state.mappings.iter_mut().find(|mapping| {
+            mapping.protocol == key.protocol
+                && mapping.outside_port == outside_port
+                && mapping.remote == remote
+        });
+        match existing {
+            Some(mapping) => {
+                if record {
+                    mapping.refresh(now);
+                }
+            }
+            None => {
+                if state.mappings.len() >= NAT_ENTRIES {
+                    log::warn!("NAT table is full!");
+                    return None;
+                }
+                if record {
+                    let mut mapping = Mapping {
+                        protocol: key.protocol,
+                        inside_addr: forward.inside_addr,
+                        inside_port,
+                        outside_port,
+                        remote,
+                        answered: false,
+                        expires_at: now,
+                    };
+                    mapping.refresh(now);
+                    state.mappings.push(mapping);
+                }
+            }
+        }
+        Some(Translation::Datagram(
+            Side::Dst,
+            forward.inside_addr,
+            inside_port,
+        ))
+    }
+
+    /// Finds the inside host an ICMP error is about, if the datagram it quotes left
+    /// through a mapping, e.g. a port unreachable. Errors don't keep a mapping alive.
+    fn icmp_error(&self, datagram: &[u8], outside_addr: Ipv4Addr) -> Option<Translation> {
+        let (key, payload) = conntrack::read_ports(datagram)?;
+        if key.protocol != ipv4::ProtocolType::IcmpV4 as u8
+            || key.dst_addr != outside_addr
+            || !payload.first().is_some_and(|&t| icmpv4::is_error(t))
+        {
+            return None;
+        }
+        let quoted = payload.get(icmpv4::HEADER_SIZE + 4..)?;
+        let quoted_key = match translatable(quoted) {
+            Some((quoted_key, _)) if quoted_key.src_addr == outside_addr => quoted_key,
+            _ => return None,
+        };
+
+        self.state
+            .lock()
+            .unwrap()
+            .mappings
+            .iter()
+            .find(|mapping| {
+                mapping.protocol == quoted_key.protocol
+                    && mapping.outside_port == quoted_key.src_port
+                    && (mapping.remote.is_none()
+                        || mapping.remote == Some((quoted_key.dst_addr, quoted_key.dst_port)))
+            })
+            .map(|mapping| Translation::IcmpError(mapping.inside_addr, mapping.inside_port))
+    }
+
+    /// Forgets mappings which haven't seen a datagram within their timeout
+    pub(crate) fn expire(&self) {
+        let now = Instant::now();
+        self.state
+            .lock()
+            .unwrap()
+            .mappings
+            .retain(|mapping| mapping.expires_at > now);
+    }
+}
+
+impl NatState {
+    /// Gives the flow `key` the next free outside port. Returns `None` if the table is
+    /// full or every port is taken.
+    fn add_mapping(&mut self, key: FlowKey, now: Instant) -> Option<u16> {
+        let port = self.free_port(key.protocol)?;
+        self.next_port = next_port(port);
+        let mut mapping = Mapping {
+            protocol: key.protocol,
+            inside_addr: key.src_addr,
+            inside_port: key.src_port,
+            outside_port: port,
+            remote: None,
+            answered: false,
+            expires_at: now,
+        };
+        mapping.refresh(now);
+        self.mappings.push(mapping);
+        Some(port)
+    }
+
+    /// The outside port `add_mapping` would give a new flow. Returns `None` if the table
+    /// is full or every port is taken.
+    fn free_port(&self, protocol: u8) -> Option<u16> {
+        if self.mappings.len() >= NAT_ENTRIES {
+            log::warn!("NAT table is full!");
+            return None;
+        }
+        let taken = |port| {
+            self.mappings
+                .iter()
+                .any(|mapping| mapping.protocol == protocol && mapping.outside_port == port)
+                || self
+                    .port_forwards
+                    .iter()
+                    .any(|forward| forward.forwards(protocol, port))
+        };
+        // One pass over the range, starting after the port given out last
+        let ports = (NAT_PORTS_END - NAT_PORTS_START) as usize + 1;
+        let port = std::iter::successors(Some(self.next_port), |&port| Some(next_port(port)))
+            .take(ports)
+            .find(|&port| !taken(port));
+        if port.is_none() {
+            log::warn!("NAT has no free outside port!");
+        }
+        port
+    }
+}
+
+fn next_port(port: u16) -> u16 {
+    match port {
+        NAT_PORTS_END => NAT_PORTS_START,
+        port => port + 1,
+    }
+}
+
+/// Which way a datagram may go through a mapping
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    /// An ICMP echo request, which is always from the host which started the flow
+    Request,
+    /// An ICMP echo reply, which always answers a request
+    Answer,
+    /// A UDP datagram, which may go either way
+    Either,
+}
+
+/// The flow key of a UDP datagram or an ICMP echo message whose whole header is present
+fn translatable(datagram: &[u8]) -> Option<(FlowKey, Kind)> {
+    let (key, payload) = conntrack::read_ports(datagram)?;
+    if key.protocol == ipv4::ProtocolType::Udp as u8 && payload.len() >= udp::HEADER_SIZE {
+        return Some((key, Kind::Either));
+    }
+    let echo_header_len = icmpv4::HEADER_SIZE + icmpv4::ECHO_HEADER_SIZE;
+    if key.protocol != ipv4::ProtocolType::IcmpV4 as u8 || payload.len() < echo_header_len {
+        return None;
+    }
+    match payload[0] {
+        t if t == icmpv4::MsgType::EchoRequest as u8 => Some((key, Kind::Request)),
+        t if t == icmpv4::MsgType::EchoReply as u8 => Some((key, Kind::Answer)),
+        _ => None,
+    }
+}
+
+/// How a datagram is translated
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Translation {
+    /// One side of the datagram gets a new address and port
+    Datagram(Side, Ipv4Addr, u16),
+    /// An ICMP error goes to this inside host and quotes the datagram as the host sent it
+    IcmpError(Ipv4Addr, u16),
+}
+
+impl Translation {
+    fn apply(self, datagram: &mut [u8]) {
+        match self {
+            Translation::Datagram(side, addr, port) => rewrite(datagram, side, addr, port),
+            Translation::IcmpError(inside_addr, inside_port) => {
+                let ip_packet = ipv4::Ipv4Packet::new_unchecked(&*datagram);
+                let header_len = ip_packet.header_len();
+                let end = std::cmp::min(datagram.len(), ip_packet.total_len() as usize);
+                let quoted_start = header_len + icmpv4::HEADER_SIZE + 4;
+                rewrite(
+                    &mut datagram[quoted_start..end],
+                    Side::Src,
+                    inside_addr,
+                    inside_port,
+                );
+                rewrite_addr(datagram, Side::Dst, inside_addr);
+                // The quoted datagram changed in several places, so the message's
+                // checksum is calculated again
+                icmpv4::Icmpv4Packet::new_unchecked(&mut datagram[header_len..end]).fill_checksum();
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Side {
+    Src,
+    Dst,
+}
+
+/// Rewrites the source or destination of a datagram checked by `translatable`. The
+/// checksums are fixed up for just the bytes which changed (RFC 1624). The datagram may
+/// be one quoted in an ICMP error, cut short after the first eight bytes of its payload.
+pub(crate) fn rewrite(datagram: &mut [u8], side: Side, addr: Ipv4Addr, port: u16) {
+    let old_addr = rewrite_addr(datagram, side, addr);
+    let ip_packet = ipv4::Ipv4Packet::new_unchecked(&*datagram);
+    let (header_len, protocol) = (ip_packet.header_len(), ip_packet.protocol());
+    let end = std::cmp::min(datagram.len(), ip_packet.total_len() as usize);
+    let payload = &mut datagram[header_len..end];
+    if protocol == ipv4::ProtocolType::Udp as u8 {
+        let port_offset = match side {
+            Side::Src => 0,
+            Side::Dst => 2,
+        };
+        let old_port = NetworkEndian::read_u16(&payload[port_offset..]);
+        NetworkEndian::write_u16(&mut payload[port_offset..], port);
+        // A zero checksum means the sender didn't calculate one
+        let checksum = NetworkEndian::read_u16(&payload[6..8]);
+        if checksum != 0 {
+            let checksum = util::update(checksum, &old_addr.octets(), &addr.octets());
+            let checksum = match util::update_word(checksum, old_port, port) {
+                0 => 0xffff,
+                checksum => checksum,
+            };
+            NetworkEndian::write_u16(&mut payload[6..8], checksum);
+        }
+    } else {
+        // The ICMP checksum doesn't cover the addresses, only the echo identifier
+        let old_id = NetworkEndian::read_u16(&payload[4..6]);
+        NetworkEndian::write_u16(&mut payload[4..6], port);
+        let checksum = NetworkEndian::read_u16(&payload[2..4]);
+        NetworkEndian::write_u16(
+            &mut payload[2..4],
+            util::update_word(checksum, old_id, port),
+        );
+    }
+}
+
+/// Rewrites the source or destination address of a datagram and fixes up its header
+/// checksum. Returns the address it replaced.
+fn rewrite_addr(datagram: &mut [u8], side: Side, addr: Ipv4Addr) -> Ipv4Addr {
+    let mut ip_packet = ipv4::Ipv4Packet::new_unchecked(datagram);
+    let old_addr = match side {
+        Side::Src => ip_packet.src_addr(),
+        Side::Dst => ip_packet.dst_addr(),
+    };
+    match side {
+        Side::Src => ip_packet.set_src_addr(addr),
+        Side::Dst => ip_packet.set_dst_addr(addr),
+    }
+    let checksum = util::update(ip_packet.checksum(), &old_addr.octets(), &addr.octets());
+    ip_packet.set_checksum(checksum);
+    old_addr
+}
+
+/// Builds a datagram with `payload` for the tests
+#[cfg(test)]
+pub(crate) fn test_datagram(builder: crate::DatagramBuilder, payload: &[u8]) -> Vec<u8> {
+    use crate::{PacketPool, DEFAULT_HEADROOM, PACKET_SIZE};
+    let buf = Box::leak(Box::new([0u8; PACKET_SIZE]));
+    let pool = PacketPool::<1>::new(buf).unwrap();
+    let mut packet = pool.allocate().unwrap();
+    packet.reserve(DEFAULT_HEADROOM).unwrap();
+    packet.put(payload.len()).unwrap().copy_from_slice(payload);
+    builder.finalize(&mut packet).unwrap();
+    let datagram = packet.as_slice().to_vec();
+    packet.discard();
+    datagram
+}
+
+mod tests {
+    #[test]
+    fn masquerades_udp() {
+        use crate::nat::*;
+        use crate::{udp, PacketBuilder};
+        let inside_host = Ipv4Addr::new(192, 168, 1, 10);
+        let server = Ipv4Addr::new(10, 0, 0, 1);
+        let outside_addr = Ipv4Addr::new(10, 0, 0, 2);
+        let mut datagram = test_datagram(
+            PacketBuilder::ipv4(inside_host, server).udp(5000, 53),
+            b"hello",
+        );
+
+        let nat = NatTable::new();
+        assert!(!nat.translate_outbound(&mut datagram, outside_addr));
+        nat.add_inside(Ipv4Cidr::new(inside_host, 24));
+        assert!(nat.translate_outbound(&mut datagram, outside_addr));
+        let ip_packet = ipv4::Ipv4Packet::new_checked(&datagram[..]).unwrap();
+        assert!(ip_packet.verify_checksum());
+        assert_eq!(ip_packet.src_addr(), outside_addr);
+        let udp_packet = udp::UdpPacket::new_checked(ip_packet.payload()).unwrap();
+        assert!(udp_packet.verify_checksum(outside_addr, server));
+        assert_eq!(udp_packet.src_port(), NAT_PORTS_START);
+
+        // The answer goes back to the inside host's port
+        let mut answer = datagram.clone();
+        rewrite(&mut answer, Side::Src, server, 53);
+        rewrite(&mut answer, Side::Dst, outside_addr, NAT_PORTS_START);
+        assert!(nat.translate_inbound(&mut answer, outside_addr));
+        let ip_packet = ipv4::Ipv4Packet::new_checked(&answer[..]).unwrap();
+        assert!(ip_packet.verify_checksum());
+        assert_eq!(ip_packet.dst_addr(), inside_host);
+        let udp_packet = udp::UdpPacket::new_checked(ip_packet.payload()).unwrap();
+        assert!(udp_packet.verify_checksum(server, inside_host));
+        assert_eq!(udp_packet.dst_port(), 5000);
+
+        assert_eq!(nat.mappings().len(), 1);
+        nat.flush();
+        assert!(!nat.translate_inbound(&mut answer, outside_addr));
+    }
+
+    #[test]
+    fn translates_icmp_errors() {
+        use crate::nat::*;
+        use crate::{icmpv4, udp, Icmpv4Message, PacketBuilder};
+        let inside_host = Ipv4Addr::new(192, 168, 1, 10);
+        let server = Ipv4Addr::new(10, 0, 0, 1);
+        let outside_addr = Ipv4Addr::new(10, 0, 0, 2);
+        let mut datagram = test_datagram(
+            PacketBuilder::ipv4(inside_host, server).udp(5000, 53),
+            b"hello",
+        );
+        let nat = NatTable::new();
+        nat.add_inside(Ipv4Cidr::new(inside_host, 24));
+        assert!(nat.translate_outbound(&mut datagram, outside_addr));
+
+        // The server says the port is unreachable, quoting the header and eight bytes
+        let mut error = test_datagram(
+            PacketBuilder::ipv4(server, outside_addr)
+                .icmpv4(Icmpv4Message::destination_unreachable(3)),
+            &datagram[..ipv4::HEADER_SIZE + udp::HEADER_SIZE],
+        );
+        let mut unknown_error = error.clone();
+
+        assert!(nat.translate_inbound(&mut error, outside_addr));
+        let ip_packet = ipv4::Ipv4Packet::new_checked(&error[..]).unwrap();
+        assert!(ip_packet.verify_checksum());
+        assert_eq!(ip_packet.dst_addr(), inside_host);
+        let icmp_packet = icmpv4::Icmpv4Packet::new_checked(ip_packet.payload()).unwrap();
+        assert!(icmp_packet.verify_checksum());
+        let quoted = &ip_packet.payload()[icmpv4::HEADER_SIZE + 4..];
+        let quoted_packet = ipv4::Ipv4Packet::new_unchecked(quoted);
+        assert!(quoted_packet.verify_checksum());
+        assert_eq!(quoted_packet.src_addr(), inside_host);
+        let udp_packet = udp::UdpPacket::new_unchecked(&quoted[ipv4::HEADER_SIZE..]);
+        assert_eq!(udp_packet.src_port(), 5000);
+
+        // An error about a flow the table doesn't know is left alone
+        nat.flush();
+        assert!(!nat.translate_inbound(&mut unknown_error, outside_addr));
+    }
+
+    #[test]
+    fn forwards_ports() {
+        use crate::nat::*;
+        use crate::{udp, PacketBuilder};
+        let client = Ipv4Addr::new(10, 0, 0, 1);
+        let outside_addr = Ipv4Addr::new(10, 0, 0, 2);
+        let server = Ipv4Addr::new(192, 168, 1, 10);
+        let mut datagram = test_datagram(
+            PacketBuilder::ipv4(client, outside_addr).udp(4000, 8053),
+            b"query",
+        );
+
+        let nat = NatTable::new();
+        let forward = PortForward {
+            protocol: ipv4::ProtocolType::Udp,
+            outside_port: 8053,
+            inside_addr: server,
+            inside_port: 53,
+        };
+        assert!(nat.add_port_forward(forward));
+        assert!(!nat.add_port_forward(forward));
+        assert!(nat.translate_inbound(&mut datagram, outside_addr));
+        let ip_packet = ipv4::Ipv4Packet::new_checked(&datagram[..]).unwrap();
+        assert!(ip_packet.verify_checksum());
+        assert_eq!(ip_packet.dst_addr(), server);
+        let udp_packet = udp::UdpPacket::new_checked(ip_packet.payload()).unwrap();
+        assert!(udp_packet.verify_checksum(client, server));
+        assert_eq!(udp_packet.dst_port(), 53);
+        assert_eq!(nat.mappings()[0].remote, Some((client, 4000)));
+
+        // The server's answer comes from the forwarded port
+        let mut answer = datagram.clone();
+        rewrite(&mut answer, Side::Src, server, 53);
+        rewrite(&mut answer, Side::Dst, client, 4000);
+        assert!(nat.translate_outbound(&mut answer, outside_addr));
+        let ip_packet = ipv4::Ipv4Packet::new_checked(&answer[..]).unwrap();
+        assert_eq!(ip_packet.src_addr(), outside_addr);
+        let udp_packet = udp::UdpPacket::new_checked(ip_packet.payload()).unwrap();
+        assert!(udp_packet.verify_checksum(outside_addr, client));
+        assert_eq!(udp_packet.src_port(), 8053);
+
+        assert_eq!(
+            nat.remove_port_forward(ipv4::ProtocolType::Udp, 8053),
+            Some(forward)
+        );
+        assert!(nat.port_forwards().is_empty());
+        assert!(nat.mappings().is_empty());
+    }
+
+    #[test]
+    fn forwards_icmp_echo_by_identifier() {
+        use crate::nat::*;
+        use crate::{icmpv4, Icmpv4Message, PacketBuilder};
+        let inside_host = Ipv4Addr::new(192, 168, 1, 10);
+        let server = Ipv4Addr::new(192, 168, 1, 20);
+        let client = Ipv4Addr::new(10, 0, 0, 1);
+        let outside_addr = Ipv4Addr::new(10, 0, 0, 2);
+        let echo = |src, dst, message| {
+            test_datagram(PacketBuilder::ipv4(src, dst).icmpv4(message), b"ping")
+        };
+        let echo_id = |datagram: &[u8]| {
+            let ip_packet = ipv4::Ipv4Packet::new_checked(datagram).unwrap();
+            let icmp_packet = icmpv4::Icmpv4Packet::new_checked(ip_packet.payload()).unwrap();
+            assert!(icmp_packet.verify_checksum());
+            (ip_packet.dst_addr(), icmp_packet.echo_id())
+        };
+
+        let nat = NatTable::new();
+        nat.add_inside(Ipv4Cidr::new(inside_host, 24));
+        assert!(nat.add_port_forward(PortForward {
+            protocol: ipv4::ProtocolType::IcmpV4,
+            outside_port: NAT_PORTS_START,
+            inside_addr: server,
+            inside_port: 1,
+        }));
+
+        // The forward only takes its own identifier, so a masqueraded ping gets the next
+        let mut datagram = echo(inside_host, client, Icmpv4Message::echo_request(7, 1));
+        assert!(nat.translate_outbound(&mut datagram, outside_addr));
+        assert_eq!(echo_id(&datagram), (client, NAT_PORTS_START + 1));
+
+        let request = Icmpv4Message::echo_request(NAT_PORTS_START, 1);
+        let mut datagram = echo(client, outside_addr, request);
+        assert!(nat.translate_inbound(&mut datagram, outside_addr));
+        assert_eq!(echo_id(&datagram), (server, 1));
+        let mut datagram = echo(client, outside_addr, Icmpv4Message::echo_request(9, 1));
+        assert!(!nat.translate_inbound(&mut datagram, outside_addr));
+
+        // The server's reply has the inside identifier, and leaves with the outside one
+        let mut datagram = echo(server, client, Icmpv4Message::echo_reply(1, 1));
+        assert!(nat.translate_outbound(&mut datagram, outside_addr));
+        assert_eq!(echo_id(&datagram), (client, NAT_PORTS_START));
+        let ip_packet = ipv4::Ipv4Packet::new_checked(&datagram[..]).unwrap();
+        assert!(ip_packet.verify_checksum());
+        assert_eq!(ip_packet.src_addr(), outside_addr);
+    }
+}
@@ -1,6 +1,11 @@
+use atomic::Atomic;
+use std::future::Future;
 use std::io;
 use std::marker::PhantomData;
-use std::sync::{Arc, Mutex};
+use std::panic::Location;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 
 #[macro_export]
 macro_rules! crate_static_pool {
@@ -12,7 +17,7 @@ macro_rules! crate_static_pool {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
-enum PacketStatus {
+pub enum PacketStatus {
     /// This slot in the packet pool is ready to be used
     Empty,
     /// This slot has been given to the caller and has not been returned
@@ -27,14 +32,24 @@ enum PacketStatus {
 
 pub const PACKET_SIZE: usize = 1568;
 
+/// Space left in front of the data of a newly allocated packet so the Ethernet, IPv4
+/// and a transport header can be pushed without moving the payload.
+pub const DEFAULT_HEADROOM: usize =
+    crate::eth::HEADER_SIZE + crate::ipv4::HEADER_SIZE + crate::udp::HEADER_SIZE;
+
 /// A wrapper around access to information about a packet in the packetpool.
+/// The packet's data lives in `buf[head..tail]`, with headroom in front of it for
+/// lower layers to push their headers and tailroom behind it for more payload.
 /// * `idx` - The index of the packet in the pool
 /// * `buf` - A pointer into the wider pool aligned on the beginning of the packet.
+/// * `head` - Offset of the first byte of packet data in the slot
+/// * `tail` - Offset one past the last byte of packet data in the slot
 /// * `pool` - A reference back to the pool the packet came from.
 pub struct Packet<'buf, 'pool, const SIZE: usize> {
     idx: usize,
     buf: *mut u8,
-    used_bytes: usize,
+    head: usize,
+    tail: usize,
     pool: &'pool PacketPool<'buf, SIZE>,
 }
 
@@ -47,7 +62,8 @@ impl<'buf, 'pool, const SIZE: usize> Packet<'buf, 'pool, SIZE> {
         Packet {
             idx,
             buf: pkt.buf,
-            used_bytes: pkt.used_bytes,
+            head: pkt.head.load(Ordering::Relaxed),
+            tail: pkt.tail.load(Ordering::Relaxed),
             pool,
         }
     }
@@ -56,103 +72,604 @@ impl<'buf, 'pool, const SIZE: usize> Packet<'buf, 'pool, SIZE> {
         PACKET_SIZE
     }
 
+    /// The number of bytes of packet data
+    pub fn len(&self) -> usize {
+        self.tail - self.head
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tail == self.head
+    }
+
+    /// Bytes free in front of the packet data for pushing headers
+    pub fn headroom(&self) -> usize {
+        self.head
+    }
+
+    /// Bytes free behind the packet data for appending payload
+    pub fn tailroom(&self) -> usize {
+        PACKET_SIZE - self.tail
+    }
+
+    /// Empties the packet and leaves `headroom` bytes in front of where its data will start
+    pub fn reserve(&mut self, headroom: usize) -> io::Result<()> {
+        if headroom > PACKET_SIZE {
+            Err(std::io::ErrorKind::InvalidInput.into())
+        } else {
+            self.head = headroom;
+            self.tail = headroom;
+            Ok(())
+        }
+    }
+
+    /// Sets the packet data to the `len` bytes following the head
+    pub fn set_len(&mut self, len: usize) -> io::Result<()> {
+        if self.head + len > PACKET_SIZE {
+            Err(std::io::ErrorKind::InvalidInput.into())
+        } else {
+            self.tail = self.head + len;
+            Ok(())
+        }
+    }
+
+    /// Grows the packet data into the headroom and returns the new header bytes
+    pub fn push_header(&mut self, len: usize) -> io::Result<&mut [u8]> {
+        if len > self.head {
+            Err(std::io::ErrorKind::InvalidInput.into())
+        } else {
+            self.head -= len;
+            let head = self.head;
+            Ok(&mut self.slot_mut()[head..head + len])
+        }
+    }
+
+    /// Strips a header from the front of the packet data and returns its bytes
+    pub fn pull_header(&mut self, len: usize) -> io::Result<&[u8]> {
+        if len > self.len() {
+            Err(std::io::ErrorKind::UnexpectedEof.into())
+        } else {
+            self.head += len;
+            Ok(&self.slot()[self.head - len..self.head])
+        }
+    }
+
+    /// Grows the packet data into the tailroom and returns the new bytes
+    pub fn put(&mut self, len: usize) -> io::Result<&mut [u8]> {
+        if len > self.tailroom() {
+            Err(std::io::ErrorKind::InvalidInput.into())
+        } else {
+            self.tail += len;
+            let tail = self.tail;
+            Ok(&mut self.slot_mut()[tail - len..tail])
+        }
+    }
+
+    /// Cuts the packet data down to `len` bytes, e.g. to remove link layer padding
+    pub fn trim(&mut self, len: usize) {
+        self.tail = std::cmp::min(self.tail, self.head + len);
+    }
+
+    /// The packet data held in the slot
+    pub fn as_slice(&self) -> &[u8] {
+        &self.slot()[self.head..self.tail]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        let (head, tail) = (self.head, self.tail);
+        &mut self.slot_mut()[head..tail]
+    }
+
+    /// The tailroom, for filling with `put` once written, e.g. by reading a frame into it
+    pub fn tailroom_mut(&mut self) -> &mut [u8] {
+        let tail = self.tail;
+        &mut self.slot_mut()[tail..]
+    }
+
+    /// Copies `buf` into the packet data at offset `idx`, growing the data if needed
     pub fn write_data(&mut self, idx: usize, buf: &[u8]) -> io::Result<()> {
-        if idx + buf.len() > PACKET_SIZE {
+        let start = self.head + idx;
+        if start + buf.len() > PACKET_SIZE {
             Err(std::io::ErrorKind::InvalidInput.into())
         } else {
-            // No need to do min here since we're pre-checking the buffer size
-            unsafe {
-                self.buf.copy_from(buf.as_ptr(), buf.len());
-            }
-            self.used_bytes = std::cmp::max(self.used_bytes, idx + buf.len());
+            self.slot_mut()[start..start + buf.len()].copy_from_slice(buf);
+            self.tail = std::cmp::max(self.tail, start + buf.len());
             Ok(())
         }
     }
 
     pub fn read_data(self, buf: &mut [u8]) -> io::Result<usize> {
-        unsafe {
-            self.buf
-                .copy_to(buf.as_mut_ptr(), std::cmp::min(buf.len(), PACKET_SIZE));
-        }
-        if buf.len() < PACKET_SIZE {
+        let data = self.as_slice();
+        if buf.len() < data.len() {
             Err(std::io::ErrorKind::UnexpectedEof.into())
         } else {
-            Ok(self.used_bytes)
+            buf[..data.len()].copy_from_slice(data);
+            Ok(data.len())
         }
     }
+
+    fn slot(&self) -> &[u8] {
+        // The slot belongs to this packet until it's released back to the pool
+        unsafe { std::slice::from_raw_parts(self.buf, PACKET_SIZE) }
+    }
+
+    fn slot_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.buf, PACKET_SIZE) }
+    }
+}
+
+impl<'buf, 'pool, const SIZE: usize> Packet<'buf, 'pool, SIZE> {
+    /// Queues the packet for transmission by the stack. The packet must hold an IPv4
+    /// datagram, e.g. from `PacketBuilder::ipv4`, with room for an Ethernet header in front
+    /// of it; the stack resolves the destination with ARP, pushes the Ethernet header and
+    /// returns the slot to the pool once sent.
+    pub fn send(self) {
+        self.pool.submit(self.idx, self.head, self.tail);
+        std::mem::forget(self);
+    }
+
+    /// Returns a packet the stack is finished with to the pool without sending it
+    pub(crate) fn discard(self) {
+        self.pool.set_status(self.idx, PacketStatus::Empty);
+        std::mem::forget(self);
+    }
+
+    pub(crate) fn set_status(&self, status: PacketStatus) {
+        self.pool.set_status(self.idx, status);
+    }
 }
 
+// The packet has the only access to its slot until it's returned to the pool
+unsafe impl<'buf, 'pool, const SIZE: usize> Send for Packet<'buf, 'pool, SIZE> {}
+
 impl<'buf, 'pool, const SIZE: usize> Drop for Packet<'buf, 'pool, SIZE> {
     fn drop(&mut self) {
         self.pool.release(self.idx);
     }
 }
 
-/// This is the interal packet representation which tracks the packet's status
-/// and can be copied as necessary. Don't let library callers have something
-/// that can be copied since we've got a pointer into the buffer
-#[derive(Clone, Copy)]
+/// This is the interal packet representation which tracks the packet's status.
+/// Every field is atomic so slots can be handed between threads without a lock;
+/// `head` and `tail` are only touched by whoever currently owns the slot.
+/// * `next_free` - The slot after this one on the free list while this slot is `Empty`
+/// * `allocated_at` - Nanoseconds from the pool's `epoch` to when the slot was allocated
+/// * `site` - Where the slot was allocated from, only recorded in debug mode
+/// * `leak_reported` - Set once the slot has been warned about as held too long
 struct PacketInner<'buf> {
-    status: PacketStatus,
+    status: Atomic<PacketStatus>,
     buf: *mut u8,
-    used_bytes: usize,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    next_free: AtomicUsize,
+    allocated_at: AtomicU64,
+    site: AtomicPtr<Location<'static>>,
+    leak_reported: AtomicBool,
     _marker: PhantomData<&'buf ()>,
 }
 
-unsafe impl<'buf> Send for PacketInner<'buf> {
-    
+impl<'buf> PacketInner<'buf> {
+    fn site(&self) -> Option<&'static Location<'static>> {
+        // Only ever set from a `&'static Location`
+        unsafe { self.site.load(Ordering::Relaxed).as_ref() }
+    }
+}
+
+/// The state of one slot in the pool when `PacketPool::diagnostics` was called
+/// * `age` - How long the slot has been out of the free list, `None` if it's `Empty`
+/// * `site` - Where the slot was allocated from, if the pool was in debug mode
+#[derive(Clone, Copy, Debug)]
+pub struct SlotDiagnostics {
+    pub idx: usize,
+    pub status: PacketStatus,
+    pub age: Option<Duration>,
+    pub site: Option<&'static Location<'static>>,
+}
+
+/// A snapshot of how the pool's slots are being used
+/// * `high_water_mark` - The most slots that have been in use at once
+#[derive(Clone, Debug)]
+pub struct PoolDiagnostics {
+    pub empty: usize,
+    pub allocated: usize,
+    pub waiting_for_arp: usize,
+    pub ready_to_transmit: usize,
+    pub ready_to_read: usize,
+    pub high_water_mark: usize,
+    pub slots: Vec<SlotDiagnostics>,
+}
+
+/// Marks the end of the free list
+const NO_SLOT: usize = u32::MAX as usize;
+
+/// The top of the free list is packed into a single word along with a counter which
+/// changes on every update, so a slot that is popped and pushed back between a load and
+/// a compare-exchange can't be mistaken for an unchanged list.
+fn pack_free_top(tag: u32, idx: usize) -> u64 {
+    ((tag as u64) << 32) | idx as u64
+}
+
+fn unpack_free_top(top: u64) -> (u32, usize) {
+    ((top >> 32) as u32, (top & 0xFFFF_FFFF) as usize)
 }
 
 /// Maintains the buffer of the packet pool and gives access to free packets
+/// * `packets` - The status and location of every slot in the pool
+/// * `free_top` - The first slot of a lock-free stack of `Empty` slots, tagged as in `pack_free_top`
+/// * `tx_queue` - Indexes of packets which are `ReadyToTransmit`, in the order they were sent
+/// * `available` - Wakes a task waiting in `allocate_async` whenever a slot is emptied
+/// * `in_use` / `high_water_mark` - The number of slots off the free list now and at most
+/// * `leak_threshold` - In debug mode, nanoseconds a slot can be held before it's
+///   reported as a possible leak. Zero when debug mode is off.
 pub struct PacketPool<'buf, const PACKETS: usize> {
-    packets: Arc<Mutex<[PacketInner<'buf>; PACKETS]>>,
+    packets: [PacketInner<'buf>; PACKETS],
+    free_top: AtomicU64,
+    tx_queue: (async_channel::Sender<usize>, async_channel::Receiver<usize>),
+    available: Notify,
+    epoch: Instant,
+    in_use: AtomicUsize,
+    high_water_mark: AtomicUsize,
+    leak_threshold: AtomicU64,
 }
 
+// Each slot's buffer is only reachable through the `Packet` which currently owns it,
+// and every shared field is atomic.
+unsafe impl<'buf, const PACKETS: usize> Send for PacketPool<'buf, PACKETS> {}
+unsafe impl<'buf, const PACKETS: usize> Sync for PacketPool<'buf, PACKETS> {}
+
 impl<'pool, 'buf, const PACKETS: usize> PacketPool<'buf, PACKETS> {
     pub fn new(buffer: &'buf mut [u8]) -> io::Result<PacketPool<'buf, PACKETS>> {
-        if buffer.len() != PACKETS * PACKET_SIZE {
+        if buffer.len() != PACKETS * PACKET_SIZE || PACKETS >= NO_SLOT {
             log::error!("Buffer provided was not the correct size");
             Err(io::ErrorKind::InvalidInput.into())
         } else {
             // Iterates over the buffer and gives pointers to evenly spaced indexes to where
-            // packets should be located in the buffer
+            // packets should be located in the buffer. Every slot starts out on the free list.
             let pool = PacketPool {
-                packets: Arc::new(Mutex::new(array_init::array_init::<_, _, PACKETS>(|idx| {
-                    PacketInner {
-                        status: PacketStatus::Empty,
-                        buf: buffer[(idx * PACKET_SIZE)..((idx + 1) * PACKET_SIZE)].as_mut_ptr(),
-                        used_bytes: 0,
-                        _marker: PhantomData,
-                    }
-                }))),
+                packets: array_init::array_init::<_, _, PACKETS>(|idx| PacketInner {
+                    status: Atomic::new(PacketStatus::Empty),
+                    buf: buffer[(idx * PACKET_SIZE)..((idx + 1) * PACKET_SIZE)].as_mut_ptr(),
+                    head: AtomicUsize::new(DEFAULT_HEADROOM),
+                    tail: AtomicUsize::new(DEFAULT_HEADROOM),
+                    next_free: AtomicUsize::new(if idx + 1 < PACKETS { idx + 1 } else { NO_SLOT }),
+                    allocated_at: AtomicU64::new(0),
+                    site: AtomicPtr::new(std::ptr::null_mut()),
+                    leak_reported: AtomicBool::new(false),
+                    _marker: PhantomData,
+                }),
+                free_top: AtomicU64::new(pack_free_top(0, if PACKETS > 0 { 0 } else { NO_SLOT })),
+                tx_queue: async_channel::bounded(PACKETS),
+                available: Notify::new(),
+                epoch: Instant::now(),
+                in_use: AtomicUsize::new(0),
+                high_water_mark: AtomicUsize::new(0),
+                leak_threshold: AtomicU64::new(0),
             };
 
             Ok(pool)
         }
     }
 
-    /// Checks the packet pool for an unused packet slot and returns one if its available.
+    /// Takes an unused packet slot off the free list and returns it if one is available.
+    #[track_caller]
     pub fn allocate(&'pool self) -> Option<Packet<'buf, 'pool, PACKETS>> {
-        let mut lock = self.packets.lock().unwrap();
-        for (idx, packet) in lock.iter_mut().enumerate() {
-            if packet.status == PacketStatus::Empty {
-                (*packet).status = PacketStatus::Allocated;
-                return Some(Packet::from_packet(idx, &packet, self));
+        self.allocate_from(Location::caller())
+    }
+
+    /// Waits until a packet slot is free and allocates it. The task is parked while the
+    /// pool is full and woken when a packet is released, so producers are held back
+    /// instead of having to poll or drop their data.
+    #[track_caller]
+    pub fn allocate_async(&'pool self) -> impl Future<Output = Packet<'buf, 'pool, PACKETS>> {
+        let site = Location::caller();
+        async move {
+            loop {
+                let released = self.available.notified();
+                if let Some(packet) = self.allocate_from(site) {
+                    return packet;
+                }
+                released.await;
             }
         }
-        None
+    }
+
+    /// Waits up to `timeout` for a packet slot to become free.
+    #[track_caller]
+    pub fn allocate_timeout(
+        &'pool self,
+        timeout: Duration,
+    ) -> impl Future<Output = Option<Packet<'buf, 'pool, PACKETS>>> {
+        let allocation = self.allocate_async();
+        async move { tokio::time::timeout(timeout, allocation).await.ok() }
+    }
+
+    fn allocate_from(
+        &'pool self,
+        site: &'static Location<'static>,
+    ) -> Option<Packet<'buf, 'pool, PACKETS>> {
+        let idx = self.pop_free()?;
+        let in_use = self.in_use.fetch_add(1, Ordering::Relaxed) + 1;
+        self.high_water_mark.fetch_max(in_use, Ordering::Relaxed);
+
+        let packet = &self.packets[idx];
+        packet
+            .status
+            .store(PacketStatus::Allocated, Ordering::Relaxed);
+        packet.head.store(DEFAULT_HEADROOM, Ordering::Relaxed);
+        packet.tail.store(DEFAULT_HEADROOM, Ordering::Relaxed);
+        packet
+            .allocated_at
+            .store(self.epoch.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        let site = if self.debug_enabled() {
+            site as *const Location<'static> as *mut Location<'static>
+        } else {
+            std::ptr::null_mut()
+        };
+        packet.site.store(site, Ordering::Relaxed);
+        packet.leak_reported.store(false, Ordering::Relaxed);
+        Some(Packet::from_packet(idx, packet, self))
+    }
+
+    /// Turns on debug mode: allocation sites are recorded and `check_leaks` warns about
+    /// packets which are held longer than `leak_threshold`.
+    pub fn enable_debug(&self, leak_threshold: Duration) {
+        // Zero is reserved for debug mode being off
+        let threshold = std::cmp::max(leak_threshold.as_nanos() as u64, 1);
+        self.leak_threshold.store(threshold, Ordering::Relaxed);
+    }
+
+    pub fn disable_debug(&self) {
+        self.leak_threshold.store(0, Ordering::Relaxed);
+    }
+
+    fn debug_enabled(&self) -> bool {
+        self.leak_threshold.load(Ordering::Relaxed) != 0
+    }
+
+    /// Counts the slots in each status and reports how long each has been in use
+    pub fn diagnostics(&self) -> PoolDiagnostics {
+        let now = self.epoch.elapsed();
+        let mut diagnostics = PoolDiagnostics {
+            empty: 0,
+            allocated: 0,
+            waiting_for_arp: 0,
+            ready_to_transmit: 0,
+            ready_to_read: 0,
+            high_water_mark: self.high_water_mark.load(Ordering::Relaxed),
+            slots: Vec::with_capacity(PACKETS),
+        };
+        for (idx, packet) in self.packets.iter().enumerate() {
+            let status = packet.status.load(Ordering::Acquire);
+            match status {
+                PacketStatus::Empty => diagnostics.empty += 1,
+                PacketStatus::Allocated => diagnostics.allocated += 1,
+                PacketStatus::WaitingForArp => diagnostics.waiting_for_arp += 1,
+                PacketStatus::ReadyToTransmit => diagnostics.ready_to_transmit += 1,
+                PacketStatus::ReadyToRead => diagnostics.ready_to_read += 1,
+            }
+            let age = if status == PacketStatus::Empty {
+                None
+            } else {
+                let allocated_at =
+                    Duration::from_nanos(packet.allocated_at.load(Ordering::Relaxed));
+                Some(now.saturating_sub(allocated_at))
+            };
+            diagnostics.slots.push(SlotDiagnostics {
+                idx,
+                status,
+                age,
+                site: packet.site(),
+            });
+        }
+        diagnostics
+    }
+
+    /// In debug mode, warns once about each packet held past the leak threshold and
+    /// returns how many packets are currently held that long.
+    pub fn check_leaks(&self) -> usize {
+        let threshold = self.leak_threshold.load(Ordering::Relaxed);
+        if threshold == 0 {
+            return 0;
+        }
+        let threshold = Duration::from_nanos(threshold);
+        let mut leaks = 0;
+        for slot in self.diagnostics().slots {
+            if let Some(age) = slot.age.filter(|&age| age >= threshold) {
+                leaks += 1;
+                let packet = &self.packets[slot.idx];
+                if !packet.leak_reported.swap(true, Ordering::Relaxed) {
+                    match slot.site {
+                        Some(site) => log::warn!(
+                            "Packet {} allocated at {} has been {:?} for {:?}",
+                            slot.idx,
+                            site,
+                            slot.status,
+                            age
+                        ),
+                        None => log::warn!(
+                            "Packet {} has been {:?} for {:?}",
+                            slot.idx,
+                            slot.status,
+                            age
+                        ),
+                    }
+                }
+            }
+        }
+        leaks
     }
 
     /// Returns a packet to the pool and out of control of client code
     /// * `pkt_idx` - Index of the packet in the pool
     fn release(&self, pkt_idx: usize) {
-        let mut lock = self.packets.lock().unwrap();
-        lock[pkt_idx].status = if lock[pkt_idx].status == PacketStatus::Allocated {
+        let packet = &self.packets[pkt_idx];
+        if packet.status.load(Ordering::Relaxed) == PacketStatus::Allocated {
             // User allocated a packet and then didn't send it
-            log::warn!("Packet allocated, but dropped without sending");
-            PacketStatus::Empty
-        } else {
-            PacketStatus::ReadyToTransmit
+            match packet.site() {
+                Some(site) => {
+                    log::warn!("Packet allocated at {}, but dropped without sending", site)
+                }
+                None => log::warn!("Packet allocated, but dropped without sending"),
+            }
         }
+        self.set_status(pkt_idx, PacketStatus::Empty);
+    }
+
+    /// Marks a packet as ready to transmit and puts it at the back of the transmit queue
+    fn submit(&self, pkt_idx: usize, head: usize, tail: usize) {
+        let packet = &self.packets[pkt_idx];
+        packet.head.store(head, Ordering::Relaxed);
+        packet.tail.store(tail, Ordering::Relaxed);
+        packet
+            .status
+            .store(PacketStatus::ReadyToTransmit, Ordering::Release);
+        // Every slot fits in the queue at once, so this can't fail while the pool is alive
+        let _ = self.tx_queue.0.try_send(pkt_idx);
+    }
+
+    /// Waits for the oldest packet which is ready to transmit. The stack owns the returned
+    /// packet and dropping it returns the slot to the pool.
+    pub(crate) async fn next_to_transmit(&'pool self) -> Packet<'buf, 'pool, PACKETS> {
+        match self.tx_queue.1.recv().await {
+            Ok(idx) => Packet::from_packet(idx, &self.packets[idx], self),
+            // The pool holds the sending side, so the queue never closes
+            Err(_) => futures::future::pending().await,
+        }
+    }
+
+    fn set_status(&self, pkt_idx: usize, status: PacketStatus) {
+        self.packets[pkt_idx]
+            .status
+            .store(status, Ordering::Release);
+        if status == PacketStatus::Empty {
+            self.push_free(pkt_idx);
+            self.in_use.fetch_sub(1, Ordering::Relaxed);
+            self.available.notify_one();
+        }
+    }
+
+    fn pop_free(&self) -> Option<usize> {
+        let mut top = self.free_top.load(Ordering::Acquire);
+        loop {
+            let (tag, idx) = unpack_free_top(top);
+            if idx == NO_SLOT {
+                return None;
+            }
+            let next = self.packets[idx].next_free.load(Ordering::Relaxed);
+            match self.free_top.compare_exchange_weak(
+                top,
+                pack_free_top(tag.wrapping_add(1), next),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(idx),
+                Err(current) => top = current,
+            }
+        }
+    }
+
+    fn push_free(&self, pkt_idx: usize) {
+        let mut top = self.free_top.load(Ordering::Acquire);
+        loop {
+            let (tag, idx) = unpack_free_top(top);
+            self.packets[pkt_idx]
+                .next_free
+                .store(idx, Ordering::Relaxed);
+            match self.free_top.compare_exchange_weak(
+                top,
+                pack_free_top(tag.wrapping_add(1), pkt_idx),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return,
+                Err(current) => top = current,
+            }
+        }
+    }
+}
+
+mod tests {
+    #[tokio::test]
+    async fn allocate_async_waits_for_release() {
+        use crate::packet_pool::*;
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE * 2]));
+        let pool = PacketPool::<2>::new(buf).unwrap();
+        let first = pool.allocate().unwrap();
+        let _second = pool.allocate().unwrap();
+        assert!(pool.allocate().is_none());
+        assert!(pool
+            .allocate_timeout(Duration::from_millis(10))
+            .await
+            .is_none());
+
+        let (packet, _) = tokio::join!(pool.allocate_async(), async move { drop(first) });
+        assert_eq!(packet.idx, 0);
+    }
+
+    #[test]
+    fn push_and_pull_headers() {
+        use crate::packet_pool::*;
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE]));
+        let pool = PacketPool::<1>::new(buf).unwrap();
+        let mut packet = pool.allocate().unwrap();
+        assert_eq!(packet.headroom(), DEFAULT_HEADROOM);
+        packet.put(4).unwrap().copy_from_slice(&[5, 6, 7, 8]);
+        packet.push_header(2).unwrap().copy_from_slice(&[3, 4]);
+        packet.write_data(6, &[9]).unwrap();
+        assert_eq!(packet.as_slice(), &[3, 4, 5, 6, 7, 8, 9]);
+        assert!(packet.push_header(DEFAULT_HEADROOM).is_err());
+
+        assert_eq!(packet.pull_header(2).unwrap(), &[3, 4]);
+        packet.trim(3);
+        assert_eq!(packet.as_slice(), &[5, 6, 7]);
+        packet.discard();
+    }
+
+    #[test]
+    fn allocate_from_many_threads() {
+        use crate::packet_pool::*;
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE * 4]));
+        let pool = PacketPool::<4>::new(buf).unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        if let Some(packet) = pool.allocate() {
+                            packet.discard();
+                        }
+                    }
+                });
+            }
+        });
+        let packets: Vec<_> = (0..4).filter_map(|_| pool.allocate()).collect();
+        assert_eq!(packets.len(), 4);
+        assert!(pool.allocate().is_none());
+    }
+
+    #[test]
+    fn diagnostics_track_slots() {
+        use crate::packet_pool::*;
+        let buf = Box::leak(Box::new([0u8; PACKET_SIZE * 3]));
+        let pool = PacketPool::<3>::new(buf).unwrap();
+        pool.enable_debug(Duration::from_nanos(1));
+        let first = pool.allocate().unwrap();
+        let second = pool.allocate().unwrap();
+        second.set_status(PacketStatus::WaitingForArp);
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(pool.check_leaks(), 2);
+
+        let diagnostics = pool.diagnostics();
+        assert_eq!(diagnostics.empty, 1);
+        assert_eq!(diagnostics.allocated, 1);
+        assert_eq!(diagnostics.waiting_for_arp, 1);
+        assert!(diagnostics.slots[0]
+            .site
+            .unwrap()
+            .file()
+            .ends_with("packet_pool.rs"));
+        assert!(diagnostics.slots[2].age.is_none());
+
+        first.discard();
+        second.discard();
+        assert_eq!(pool.diagnostics().empty, 3);
+        assert_eq!(pool.diagnostics().high_water_mark, 2);
     }
 }
@@ -0,0 +1,145 @@
+use crate::{eth, ipv4, util, PACKET_SIZE};
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// How many priority queues the egress scheduler has. Queue 0 is sent first.
+pub const PRIORITY_QUEUES: usize = 4;
+/// The default number of frames each priority queue holds
+pub const DEFAULT_QUEUE_LEN: usize = 64;
+
+/// Limits on the frames the stack writes to its device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShaperConfig {
+    /// Bytes per second the device may be sent on average. Zero is taken as one.
+    pub rate: u64,
+    /// Bytes which may be sent back to back after the link was idle. It's raised to at
+    /// least one full frame.
+    pub burst: u64,
+    /// Frames each priority queue holds before new ones are dropped
+    pub queue_len: usize,
+}
+
+impl ShaperConfig {
+    /// Limits the device to `rate` bytes per second, in bursts of a tenth of a second
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            burst: rate / 10,
+            queue_len: DEFAULT_QUEUE_LEN,
+        }
+    }
+}
+
+/// Picks the queue for a frame. ARP, RARP and IGMP frames, and IPv4 datagrams marked as
+/// network control (DSCP 48 and up), go first. Other datagrams are queued by the top two
+/// bits of their DSCP, so best effort traffic lands in the last queue.
+pub fn priority(frame: &[u8]) -> usize {
+    let eth_frame = match eth::EthernetFrame::new_checked(frame) {
+        Ok(eth_frame) => eth_frame,
+        Err(_) => return PRIORITY_QUEUES - 1,
+    };
+    if eth_frame.ethertype() != eth::Ethertype::IPv4 as u16 {
+        return 0;
+    }
+    let ip_packet = match ipv4::Ipv4Packet::new_checked(eth_frame.payload()) {
+        Ok(ip_packet) => ip_packet,
+        Err(_) => return PRIORITY_QUEUES - 1,
+    };
+    if ip_packet.protocol() == ipv4::ProtocolType::Igmp as u8 {
+        return 0;
+    }
+    let dscp = ip_packet.type_of_service() >> 2;
+    PRIORITY_QUEUES - 1 - (dscp >> 4) as usize
+}
+
+/// Holds frames waiting to be written to the device, and lets them out at the
+/// configured rate, highest priority first.
+pub(crate) struct Shaper {
+    queue_len: usize,
+    bucket: util::TokenBucket,
+    queues: [VecDeque<Vec<u8>>; PRIORITY_QUEUES],
+}
+
+impl Shaper {
+    pub fn new(config: ShaperConfig) -> Self {
+        let burst = std::cmp::max(config.burst, PACKET_SIZE as u64);
+        Self {
+            queue_len: config.queue_len,
+            bucket: util::TokenBucket::new(config.rate.max(1) as f64, burst as f64),
+            queues: Default::default(),
+        }
+    }
+
+    /// Adds a copy of `frame` to the end of `queue`. Returns false if the queue is full,
+    /// so the frame was dropped.
+    pub fn enqueue(&mut self, queue: usize, frame: &[u8]) -> bool {
+        if self.queues[queue].len() >= self.queue_len {
+            return false;
+        }
+        self.queues[queue].push_back(frame.to_vec());
+        true
+    }
+
+    /// Takes the next frame if the rate limit allows it to be sent now, with the queue
+    /// it came from
+    pub fn dequeue(&mut self) -> Option<(usize, Vec<u8>)> {
+        let queue = self.queues.iter().position(|queue| !queue.is_empty())?;
+        let len = self.queues[queue].front()?.len();
+        if !self.bucket.try_take(len as f64) {
+            return None;
+        }
+        self.queues[queue].pop_front().map(|frame| (queue, frame))
+    }
+
+    /// When the next queued frame may be sent, if there is one
+    pub fn next_send_at(&mut self) -> Option<Instant> {
+        let frame = self.queues.iter().find_map(|queue| queue.front())?;
+        Some(Instant::now() + self.bucket.time_until(frame.len() as f64))
+    }
+
+    /// How many frames are waiting in each queue
+    pub fn queued(&self) -> [usize; PRIORITY_QUEUES] {
+        array_init::array_init(|idx| self.queues[idx].len())
+    }
+}
+
+mod tests {
+    #[test]
+    fn sends_by_priority_within_rate() {
+        use crate::shaper::*;
+        let frame = |ethertype: eth::Ethertype, tos: u8| {
+            let mut frame = vec![0u8; 1000];
+            frame[12..14].copy_from_slice(&(ethertype as u16).to_be_bytes());
+            let datagram = &mut frame[eth::HEADER_SIZE..];
+            datagram[0] = 0x45;
+            datagram[1] = tos;
+            datagram[2..4].copy_from_slice(&28u16.to_be_bytes());
+            datagram[9] = ipv4::ProtocolType::Udp as u8;
+            frame
+        };
+        let best_effort = frame(eth::Ethertype::IPv4, 0);
+        let expedited = frame(eth::Ethertype::IPv4, 46 << 2);
+        let arp = frame(eth::Ethertype::ARP, 0);
+        assert_eq!(priority(&best_effort), 3);
+        assert_eq!(priority(&expedited), 1);
+        assert_eq!(priority(&arp), 0);
+
+        // A burst of one full frame, refilled far slower than the test runs, so only the
+        // ARP frame goes out
+        let mut shaper = Shaper::new(ShaperConfig {
+            rate: 1,
+            burst: 0,
+            queue_len: 2,
+        });
+        assert!(shaper.enqueue(priority(&best_effort), &best_effort));
+        assert!(shaper.enqueue(priority(&best_effort), &best_effort));
+        assert!(!shaper.enqueue(priority(&best_effort), &best_effort));
+        assert!(shaper.enqueue(priority(&arp), &arp));
+        assert_eq!(shaper.queued(), [1, 0, 0, 2]);
+
+        assert_eq!(shaper.dequeue().map(|(queue, _)| queue), Some(0));
+        assert_eq!(shaper.dequeue(), None);
+        assert_eq!(shaper.queued(), [0, 0, 0, 2]);
+        assert!(shaper.next_send_at().unwrap() > Instant::now());
+    }
+}
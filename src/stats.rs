@@ -0,0 +1,281 @@
+use crate::eth;
+use crate::shaper::PRIORITY_QUEUES;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The reasons the stack may discard a frame instead of processing or sending it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DropReason {
+    /// The frame or one of its headers could not be decoded
+    DecodeError,
+    /// A header or payload checksum did not match its contents
+    BadChecksum,
+    /// The packet was addressed to some other host
+    NotForUs,
+    /// No ARP entry exists for the next hop of an outgoing packet
+    ArpMiss,
+    /// There was no free packet slot in the pool to hold the frame
+    PoolExhausted,
+    /// The sender was over its allowance, e.g. the ARP rate limit
+    RateLimited,
+    /// A packet filter rule dropped or rejected the frame
+    Filtered,
+    /// The frame's egress queue was full
+    QueueFull,
+    /// A datagram to pass on had run out of hops
+    TtlExpired,
+}
+
+impl DropReason {
+    /// Every reason, in declaration order
+    pub const ALL: [DropReason; 9] = [
+        DropReason::DecodeError,
+        DropReason::BadChecksum,
+        DropReason::NotForUs,
+        DropReason::ArpMiss,
+        DropReason::PoolExhausted,
+        DropReason::RateLimited,
+        DropReason::Filtered,
+        DropReason::QueueFull,
+        DropReason::TtlExpired,
+    ];
+
+    pub const COUNT: usize = DropReason::ALL.len();
+}
+
+// Stops compiling when a reason is added, until it's added to `DropReason::ALL` too
+const _: () = {
+    let mut idx = 0;
+    while idx < DropReason::COUNT {
+        assert!(DropReason::ALL[idx] as usize == idx);
+        idx += 1;
+    }
+    match DropReason::DecodeError {
+        DropReason::DecodeError
+        | DropReason::BadChecksum
+        | DropReason::NotForUs
+        | DropReason::ArpMiss
+        | DropReason::PoolExhausted
+        | DropReason::RateLimited
+        | DropReason::Filtered
+        | DropReason::QueueFull
+        | DropReason::TtlExpired => {}
+    }
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EthertypeCounts {
+    pub ipv4: u64,
+    pub arp: u64,
+    pub rarp: u64,
+    pub other: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DropCounts {
+    pub decode_error: u64,
+    pub bad_checksum: u64,
+    pub not_for_us: u64,
+    pub arp_miss: u64,
+    pub pool_exhausted: u64,
+    pub rate_limited: u64,
+    pub filtered: u64,
+    pub queue_full: u64,
+    pub ttl_expired: u64,
+}
+
+/// Frames which went through one of the egress scheduler's priority queues
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueueCounts {
+    pub sent: u64,
+    pub bytes: u64,
+    pub dropped: u64,
+}
+
+/// A point in time copy of the stack's counters.
+/// * `ipv4_in_by_proto` - Received IPv4 packets indexed by protocol number
+/// * `icmp_in_by_type` - Received ICMP messages indexed by message type
+/// * `icmp_out_by_type` - Sent ICMP messages indexed by message type
+/// * `arp_conflicts` - ARP packets claiming a known address for a different MAC address
+/// * `egress_queues` - Frames sent or dropped by each egress priority queue, while
+///   shaping is enabled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StatsSnapshot {
+    pub frames_in: EthertypeCounts,
+    pub frames_out: EthertypeCounts,
+    pub arp_requests_in: u64,
+    pub arp_replies_in: u64,
+    pub arp_requests_out: u64,
+    pub arp_replies_out: u64,
+    pub arp_conflicts: u64,
+    pub ipv4_in_by_proto: [u64; 256],
+    pub icmp_in_by_type: [u64; 256],
+    pub icmp_out_by_type: [u64; 256],
+    pub drops: DropCounts,
+    pub egress_queues: [QueueCounts; PRIORITY_QUEUES],
+}
+
+#[derive(Default)]
+struct AtomicEthertypeCounts {
+    ipv4: AtomicU64,
+    arp: AtomicU64,
+    rarp: AtomicU64,
+    other: AtomicU64,
+}
+
+impl AtomicEthertypeCounts {
+    fn record(&self, ethertype: Option<eth::Ethertype>) {
+        let counter = match ethertype {
+            Some(eth::Ethertype::IPv4) => &self.ipv4,
+            Some(eth::Ethertype::ARP) => &self.arp,
+            Some(eth::Ethertype::RARP) => &self.rarp,
+            None => &self.other,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> EthertypeCounts {
+        EthertypeCounts {
+            ipv4: self.ipv4.load(Ordering::Relaxed),
+            arp: self.arp.load(Ordering::Relaxed),
+            rarp: self.rarp.load(Ordering::Relaxed),
+            other: self.other.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counters maintained by the stack while it runs. They're shared with the
+/// application through an `Arc` so they can be read while the stack is running.
+pub struct Stats {
+    frames_in: AtomicEthertypeCounts,
+    frames_out: AtomicEthertypeCounts,
+    arp_requests_in: AtomicU64,
+    arp_replies_in: AtomicU64,
+    arp_requests_out: AtomicU64,
+    arp_replies_out: AtomicU64,
+    arp_conflicts: AtomicU64,
+    ipv4_in_by_proto: [AtomicU64; 256],
+    icmp_in_by_type: [AtomicU64; 256],
+    icmp_out_by_type: [AtomicU64; 256],
+    drops: [AtomicU64; DropReason::COUNT],
+    egress_sent: [AtomicU64; PRIORITY_QUEUES],
+    egress_bytes: [AtomicU64; PRIORITY_QUEUES],
+    egress_dropped: [AtomicU64; PRIORITY_QUEUES],
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            frames_in: AtomicEthertypeCounts::default(),
+            frames_out: AtomicEthertypeCounts::default(),
+            arp_requests_in: AtomicU64::new(0),
+            arp_replies_in: AtomicU64::new(0),
+            arp_requests_out: AtomicU64::new(0),
+            arp_replies_out: AtomicU64::new(0),
+            arp_conflicts: AtomicU64::new(0),
+            ipv4_in_by_proto: array_init::array_init(|_| AtomicU64::new(0)),
+            icmp_in_by_type: array_init::array_init(|_| AtomicU64::new(0)),
+            icmp_out_by_type: array_init::array_init(|_| AtomicU64::new(0)),
+            drops: array_init::array_init(|_| AtomicU64::new(0)),
+            egress_sent: array_init::array_init(|_| AtomicU64::new(0)),
+            egress_bytes: array_init::array_init(|_| AtomicU64::new(0)),
+            egress_dropped: array_init::array_init(|_| AtomicU64::new(0)),
+        }
+    }
+
+    /// Copies the current value of every counter.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let load_all = |counters: &[AtomicU64; 256]| -> [u64; 256] {
+            array_init::array_init(|idx| counters[idx].load(Ordering::Relaxed))
+        };
+        let drops = |reason: DropReason| self.drops[reason as usize].load(Ordering::Relaxed);
+        StatsSnapshot {
+            frames_in: self.frames_in.snapshot(),
+            frames_out: self.frames_out.snapshot(),
+            arp_requests_in: self.arp_requests_in.load(Ordering::Relaxed),
+            arp_replies_in: self.arp_replies_in.load(Ordering::Relaxed),
+            arp_requests_out: self.arp_requests_out.load(Ordering::Relaxed),
+            arp_replies_out: self.arp_replies_out.load(Ordering::Relaxed),
+            arp_conflicts: self.arp_conflicts.load(Ordering::Relaxed),
+            ipv4_in_by_proto: load_all(&self.ipv4_in_by_proto),
+            icmp_in_by_type: load_all(&self.icmp_in_by_type),
+            icmp_out_by_type: load_all(&self.icmp_out_by_type),
+            drops: DropCounts {
+                decode_error: drops(DropReason::DecodeError),
+                bad_checksum: drops(DropReason::BadChecksum),
+                not_for_us: drops(DropReason::NotForUs),
+                arp_miss: drops(DropReason::ArpMiss),
+                pool_exhausted: drops(DropReason::PoolExhausted),
+                rate_limited: drops(DropReason::RateLimited),
+                filtered: drops(DropReason::Filtered),
+                queue_full: drops(DropReason::QueueFull),
+                ttl_expired: drops(DropReason::TtlExpired),
+            },
+            egress_queues: array_init::array_init(|queue| QueueCounts {
+                sent: self.egress_sent[queue].load(Ordering::Relaxed),
+                bytes: self.egress_bytes[queue].load(Ordering::Relaxed),
+                dropped: self.egress_dropped[queue].load(Ordering::Relaxed),
+            }),
+        }
+    }
+
+    pub(crate) fn record_frame_in(&self, ethertype: Option<eth::Ethertype>) {
+        self.frames_in.record(ethertype);
+    }
+
+    pub(crate) fn record_frame_out(&self, ethertype: Option<eth::Ethertype>) {
+        self.frames_out.record(ethertype);
+    }
+
+    pub(crate) fn record_arp_in(&self, request: bool) {
+        if request {
+            self.arp_requests_in.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.arp_replies_in.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_arp_out(&self, request: bool) {
+        if request {
+            self.arp_requests_out.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.arp_replies_out.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_arp_conflict(&self) {
+        self.arp_conflicts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_ipv4_in(&self, proto: u8) {
+        self.ipv4_in_by_proto[proto as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_icmp_in(&self, msg_type: u8) {
+        self.icmp_in_by_type[msg_type as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_icmp_out(&self, msg_type: u8) {
+        self.icmp_out_by_type[msg_type as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_drop(&self, reason: DropReason) {
+        self.drops[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_egress_sent(&self, queue: usize, len: usize) {
+        self.egress_sent[queue].fetch_add(1, Ordering::Relaxed);
+        self.egress_bytes[queue].fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a frame dropped because its egress queue was full
+    pub(crate) fn record_egress_drop(&self, queue: usize) {
+        self.egress_dropped[queue].fetch_add(1, Ordering::Relaxed);
+        self.record_drop(DropReason::QueueFull);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::error::{check_decode_len, check_encode_len, Layer, Result};
+use crate::{ipv4, util};
+use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt, WriteBytesExt};
+use std::io;
+use std::net::Ipv4Addr;
+
+pub const HEADER_SIZE: usize = 8;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub length: u16,
+    pub checksum: u16,
+}
+
+impl Header {
+    pub fn decode(buf: &[u8]) -> Result<(Self, &[u8])> {
+        check_decode_len(Layer::Udp, buf, HEADER_SIZE)?;
+        let mut cursor = io::Cursor::new(buf);
+        let src_port = cursor.read_u16::<NetworkEndian>()?;
+        let dst_port = cursor.read_u16::<NetworkEndian>()?;
+        let length = cursor.read_u16::<NetworkEndian>()?;
+        let checksum = cursor.read_u16::<NetworkEndian>()?;
+        Ok((
+            Self {
+                src_port,
+                dst_port,
+                length,
+                checksum,
+            },
+            &buf[HEADER_SIZE..],
+        ))
+    }
+
+    pub fn encode(self, buf: &mut [u8]) -> Result<usize> {
+        check_encode_len(Layer::Udp, buf, HEADER_SIZE)?;
+        let mut cursor = io::Cursor::new(buf);
+        cursor.write_u16::<NetworkEndian>(self.src_port)?;
+        cursor.write_u16::<NetworkEndian>(self.dst_port)?;
+        cursor.write_u16::<NetworkEndian>(self.length)?;
+        cursor.write_u16::<NetworkEndian>(self.checksum)?;
+        Ok(HEADER_SIZE)
+    }
+}
+
+/// Calculates the checksum of `datagram`, which also covers a pseudo-header made from the
+/// IP addresses, protocol and UDP length. A checksum of zero means none was calculated, so
+/// it's sent as all ones.
+pub fn checksum(src_addr: Ipv4Addr, dst_addr: Ipv4Addr, datagram: &[u8]) -> u16 {
+    let mut sum = util::Checksum::new();
+    sum.add(&src_addr.octets())
+        .add(&dst_addr.octets())
+        .add_word(ipv4::ProtocolType::Udp as u16)
+        .add_word(datagram.len() as u16)
+        .add(datagram);
+    match sum.finish() {
+        0 => 0xffff,
+        checksum => checksum,
+    }
+}
+
+/// A view of a UDP datagram which reads and writes the header fields directly in `buffer`
+#[derive(Clone, Copy, Debug)]
+pub struct UdpPacket<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> UdpPacket<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    /// Makes sure the buffer holds the header and the length it claims
+    pub fn new_checked(buffer: T) -> Result<Self> {
+        check_decode_len(Layer::Udp, buffer.as_ref(), HEADER_SIZE)?;
+        let packet = Self { buffer };
+        let needed = std::cmp::max(packet.length() as usize, HEADER_SIZE);
+        check_decode_len(Layer::Udp, packet.buffer.as_ref(), needed)?;
+        Ok(packet)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    pub fn src_port(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[0..2])
+    }
+
+    pub fn dst_port(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[2..4])
+    }
+
+    pub fn length(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[4..6])
+    }
+
+    pub fn checksum(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buffer.as_ref()[6..8])
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        let length = std::cmp::max(self.length() as usize, HEADER_SIZE);
+        &self.buffer.as_ref()[HEADER_SIZE..length]
+    }
+
+    /// Checks the checksum over the pseudo-header and datagram. A datagram sent without a
+    /// checksum always passes.
+    pub fn verify_checksum(&self, src_addr: Ipv4Addr, dst_addr: Ipv4Addr) -> bool {
+        if self.checksum() == 0 {
+            return true;
+        }
+        let length = std::cmp::max(self.length() as usize, HEADER_SIZE);
+        let mut sum = util::Checksum::new();
+        sum.add(&src_addr.octets())
+            .add(&dst_addr.octets())
+            .add_word(ipv4::ProtocolType::Udp as u16)
+            .add_word(length as u16)
+            .add(&self.buffer.as_ref()[..length]);
+        sum.finish() == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> UdpPacket<T> {
+    pub fn set_src_port(&mut self, port: u16) {
+        NetworkEndian::write_u16(&mut self.buffer.as_mut()[0..2], port);
+    }
+
+    pub fn set_dst_port(&mut self, port: u16) {
+        NetworkEndian::write_u16(&mut self.buffer.as_mut()[2..4], port);
+    }
+
+    pub fn set_length(&mut self, length: u16) {
+        NetworkEndian::write_u16(&mut self.buffer.as_mut()[4..6], length);
+    }
+
+    pub fn set_checksum(&mut self, checksum: u16) {
+        NetworkEndian::write_u16(&mut self.buffer.as_mut()[6..8], checksum);
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let length = std::cmp::max(self.length() as usize, HEADER_SIZE);
+        &mut self.buffer.as_mut()[HEADER_SIZE..length]
+    }
+
+    /// Recalculates the checksum over the pseudo-header and datagram
+    pub fn fill_checksum(&mut self, src_addr: Ipv4Addr, dst_addr: Ipv4Addr) {
+        self.set_checksum(0);
+        let length = std::cmp::max(self.length() as usize, HEADER_SIZE);
+        let checksum = checksum(src_addr, dst_addr, &self.buffer.as_ref()[..length]);
+        self.set_checksum(checksum);
+    }
+}
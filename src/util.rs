@@ -1,24 +1,204 @@
-use byteorder::{NetworkEndian, ReadBytesExt};
-use std::io;
+use std::time::{Duration, Instant};
 
+/// Computes the Internet checksum (RFC 1071) of `buf`. A trailing odd byte is
+/// treated as if it were followed by a zero byte.
 pub fn checksum(buf: &[u8]) -> u16 {
-    let mut sum: u32 = 0;
-    let mut cursor = io::Cursor::new(buf);
+    let mut sum = Checksum::new();
+    sum.add(buf);
+    sum.finish()
+}
+
+/// Updates `checksum` for a header in which the 16 bit word `old` was replaced by
+/// `new`, without summing the rest of the header again (RFC 1624, eqn. 3).
+pub fn update_word(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = Checksum::new();
+    sum.add_word(!checksum);
+    sum.add_word(!old);
+    sum.add_word(new);
+    sum.finish()
+}
+
+/// Updates `checksum` for a header in which the bytes `old` were replaced by `new`, e.g.
+/// an address rewrite. Both slices must have the same even length and start at an even
+/// offset into the checksummed data.
+pub fn update(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    debug_assert_eq!(old.len(), new.len());
+    debug_assert_eq!(old.len() % 2, 0);
+    let mut sum = Checksum::new();
+    sum.add_word(!checksum);
+    for word in old.chunks_exact(2) {
+        sum.add_word(!u16::from_be_bytes([word[0], word[1]]));
+    }
+    sum.add(new);
+    sum.finish()
+}
+
+/// Accumulates the Internet checksum over data which isn't contiguous, such as a
+/// pseudo-header followed by a payload. Slices may have odd lengths; the bytes are
+/// summed as if they had been concatenated.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Checksum {
+    sum: u64,
+    /// The first byte of a word which was split between two slices
+    odd_byte: Option<u8>,
+}
+
+impl Checksum {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, mut buf: &[u8]) -> &mut Self {
+        if buf.is_empty() {
+            return self;
+        }
+        if let Some(high) = self.odd_byte.take() {
+            self.add_word(u16::from_be_bytes([high, buf[0]]));
+            buf = &buf[1..];
+        }
+
+        // Ones' complement addition doesn't depend on the word size, so the data is summed
+        // eight bytes at a time with the carry wrapped around and folded at the end
+        let mut chunks = buf.chunks_exact(8);
+        for chunk in &mut chunks {
+            let word = u64::from_be_bytes(chunk.try_into().unwrap());
+            self.add_u64(word);
+        }
+        let mut words = chunks.remainder().chunks_exact(2);
+        for word in &mut words {
+            self.add_word(u16::from_be_bytes([word[0], word[1]]));
+        }
+        if let [byte] = words.remainder() {
+            self.odd_byte = Some(*byte);
+        }
+        self
+    }
+
+    /// Adds a 16 bit word. It must fall on a word boundary, so it can't follow an `add`
+    /// of an odd number of bytes.
+    pub fn add_word(&mut self, word: u16) -> &mut Self {
+        debug_assert!(self.odd_byte.is_none(), "add_word after an odd length add");
+        self.add_u64(word as u64);
+        self
+    }
+
+    fn add_u64(&mut self, word: u64) {
+        let (sum, carry) = self.sum.overflowing_add(word);
+        self.sum = sum + carry as u64;
+    }
+
+    /// Folds the sum to 16 bits and returns its complement, ready to be written into a
+    /// header.
+    pub fn finish(&self) -> u16 {
+        let mut sum = self.sum;
+        if let Some(high) = self.odd_byte {
+            let (folded, carry) = sum.overflowing_add((high as u64) << 8);
+            sum = folded + carry as u64;
+        }
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+}
+
+/// Allows `rate` units per second on average, in bursts of up to `burst` units.
+#[derive(Clone, Copy, Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    /// Makes a bucket which starts full
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            updated_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated_at = now;
+    }
+
+    /// Takes `amount` tokens if there are enough
+    pub fn try_take(&mut self, amount: f64) -> bool {
+        self.refill(Instant::now());
+        if self.tokens >= amount {
+            self.tokens -= amount;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long until the bucket holds `amount` tokens
+    pub fn time_until(&mut self, amount: f64) -> Duration {
+        self.refill(Instant::now());
+        if self.tokens >= amount {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((amount - self.tokens) / self.rate)
+    }
+
+    /// Whether the bucket has refilled completely, so it can be forgotten
+    pub fn is_full(&mut self) -> bool {
+        self.refill(Instant::now());
+        self.tokens >= self.burst
+    }
+}
 
-    let mut counter = buf.len();
-    while counter >= 2 {
-        sum += cursor.read_u16::<NetworkEndian>().unwrap() as u32;
-        counter -= 2;
+/// SplitMix64, a small generator which gives the same numbers for the same seed. It's
+/// fine for spreading out timers and emulating loss, not for anything secret. The
+/// default generator has a random seed.
+#[derive(Clone, Copy, Debug)]
+pub struct Rng(u64);
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::from_random_seed()
     }
-    if counter == 1 {
-        sum += cursor.read_u8().unwrap() as u32;
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
     }
 
-    while sum > 0xffff {
-        sum = (sum & 0xFFFF) + (sum >> 16);
+    /// Seeds a generator from the random keys std gives each `HashMap`
+    pub fn from_random_seed() -> Self {
+        use std::hash::{BuildHasher, Hasher};
+        Self(
+            std::collections::hash_map::RandomState::new()
+                .build_hasher()
+                .finish(),
+        )
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number from 0 up to but not including 1
+    pub fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// True with the given probability
+    pub fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.unit() < probability
     }
-    let sum = sum as u16;
-    sum ^ 0xffff
 }
 
 mod tests {
@@ -31,4 +211,44 @@ mod tests {
         ];
         assert_eq!(checksum(&buf), 0xe4c0);
     }
+
+    #[test]
+    fn split_checksum() {
+        use crate::util::*;
+        let buf: [u8; 20] = [
+            0x45, 0x00, 0x00, 0x54, 0x41, 0xe0, 0x40, 0x00, 0x40, 0x01, 0x00, 0x00, 0x0a, 0x00,
+            0x00, 0x04, 0x0a, 0x00, 0x00, 0x05,
+        ];
+        // Splitting the header at any point, including mid word, gives the same sum
+        for split in 0..buf.len() {
+            let mut sum = Checksum::new();
+            sum.add(&buf[..split]).add(&buf[split..]);
+            assert_eq!(sum.finish(), 0xe4c0);
+        }
+    }
+
+    #[test]
+    fn incremental_update() {
+        use crate::util::*;
+        let mut buf: [u8; 20] = [
+            0x45, 0x00, 0x00, 0x54, 0x41, 0xe0, 0x40, 0x00, 0x40, 0x01, 0xe4, 0xc0, 0x0a, 0x00,
+            0x00, 0x04, 0x0a, 0x00, 0x00, 0x05,
+        ];
+
+        // Decrement the TTL
+        let old = u16::from_be_bytes([buf[8], buf[9]]);
+        buf[8] -= 1;
+        let new = u16::from_be_bytes([buf[8], buf[9]]);
+        let updated = update_word(0xe4c0, old, new);
+        buf[10..12].fill(0);
+        assert_eq!(updated, checksum(&buf));
+        buf[10..12].copy_from_slice(&updated.to_be_bytes());
+
+        // Rewrite the source address
+        let old_addr: [u8; 4] = buf[12..16].try_into().unwrap();
+        buf[12..16].copy_from_slice(&[192, 168, 1, 200]);
+        let updated = update(updated, &old_addr, &buf[12..16]);
+        buf[10..12].fill(0);
+        assert_eq!(updated, checksum(&buf));
+    }
 }